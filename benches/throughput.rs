@@ -0,0 +1,116 @@
+//! Ingestion throughput benchmarks.
+//!
+//! Run with `cargo bench --bench throughput`. Measures packet parsing on
+//! its own, then end to end UDP ingestion with different worker counts.
+
+extern crate statsd;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use statsd::backend::Backend;
+use statsd::buckets::Buckets;
+use statsd::events::ParsedPacket;
+use statsd::server::Server;
+
+
+const CLIENTS: usize = 4;
+const SECONDS: u64 = 3;
+
+
+/// Reports the number of messages received in each flush.
+struct MessageCount(Sender<usize>);
+
+impl Backend for MessageCount {
+    fn flush_buckets(&mut self, buckets: &Buckets) {
+        let _ = self.0.send(buckets.total_messages());
+    }
+}
+
+
+/// Build a packet of metric lines close to the default client packet size.
+fn packet(client: usize) -> Vec<u8> {
+    let mut lines = Vec::new();
+    let mut len = 0;
+    let mut i = 0;
+    while len < 1400 {
+        let line = match i % 4 {
+            0 => format!("bench.counter.{}.{}:1|c", client, i),
+            1 => format!("bench.timer.{}.{}:{}|ms|@0.5", client, i, i * 3),
+            2 => format!("bench.gauge.{}.{}:{}|g|#env:bench", client, i, i),
+            _ => format!("bench.set.{}:{}|s", client, i),
+        };
+        len += line.len() + 1;
+        lines.push(line);
+        i += 1;
+    }
+    lines.join("\n").into_bytes()
+}
+
+fn bench_parse() {
+    let packet = packet(0);
+    let lines = ParsedPacket::parse(&packet, false).messages.len();
+    let iterations = 20_000;
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        ParsedPacket::parse(&packet, false);
+    }
+    let secs = start.elapsed().as_secs_f64();
+    println!("parse: {:.0} packets/s, {:.0} lines/s",
+             iterations as f64 / secs,
+             (iterations * lines) as f64 / secs);
+}
+
+fn bench_udp(workers: usize) {
+    let (send, recv) = channel();
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let server = Server::new()
+                     .udp_addr(local)
+                     .admin_addr(local)
+                     .udp_workers(workers)
+                     .flush_interval(1)
+                     .backend(Box::new(MessageCount(send)))
+                     .start()
+                     .unwrap();
+    let addr = server.udp_addr();
+
+    let running = Arc::new(AtomicBool::new(true));
+    let clients: Vec<_> = (0..CLIENTS).map(|i| {
+                                          let running = running.clone();
+                                          thread::spawn(move || {
+                                              let socket = UdpSocket::bind(local).unwrap();
+                                              let packet = packet(i);
+                                              while running.load(Ordering::Relaxed) {
+                                                  let _ = socket.send_to(&packet, addr);
+                                              }
+                                          })
+                                      })
+                                      .collect();
+
+    // Take the best full flush interval as the steady state rate.
+    let mut best = 0;
+    let deadline = Instant::now() + Duration::from_secs(SECONDS);
+    while Instant::now() < deadline {
+        if let Ok(count) = recv.recv_timeout(Duration::from_secs(2)) {
+            best = best.max(count);
+        }
+    }
+    running.store(false, Ordering::Relaxed);
+    for client in clients {
+        client.join().unwrap();
+    }
+    server.shutdown();
+    println!("udp workers={}: {} messages/s", workers, best);
+}
+
+fn main() {
+    bench_parse();
+    for workers in [1, 2, 4] {
+        bench_udp(workers);
+    }
+}
@@ -0,0 +1,11 @@
+//! Fuzz the packet parser with arbitrary packets.
+//!
+//! Run with `cargo fuzz run parse_packet` from the repository root.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use statsd::events::ParsedPacket;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ParsedPacket::parse(data, false);
+    let _ = ParsedPacket::parse(data, true);
+});
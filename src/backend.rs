@@ -1,33 +1,44 @@
+use std::io;
+
 use buckets::Buckets;
+use events::{Event, ServiceCheck};
 use backends::console;
 use backends::graphite;
 
 /// Defines the interface that backends use to publish
 /// metrics to their storage system.
-pub trait Backend {
+pub trait Backend: Send {
     /// This method should flush the current data to the backend.
     ///
     /// Called on server `flush` events, which occur on a timer
     /// (every 10 seconds by default).
     fn flush_buckets(&mut self, buckets: &Buckets) -> ();
+
+    /// This method should publish the events and service checks
+    /// received since the last flush.
+    ///
+    /// Called after `flush_buckets`. Backends that have no use for
+    /// events can rely on the default which ignores them.
+    fn flush_events(&mut self, _events: &[Event], _service_checks: &[ServiceCheck]) {}
 }
 
 
 /// Creates the collection of backends based on the paraemeters
 ///
+/// Returns an error if the graphite host can't be resolved.
 pub fn factory(console: &bool,
                graphite: &bool,
                graphite_host: &str,
                graphite_port: &u16)
-               -> Box<[Box<Backend>]> {
-    let mut backends: Vec<Box<Backend>> = Vec::with_capacity(2);
+               -> io::Result<Box<[Box<dyn Backend>]>> {
+    let mut backends: Vec<Box<dyn Backend>> = Vec::with_capacity(2);
     if *console {
         backends.push(Box::new(console::Console::new()));
     }
     if *graphite {
-        backends.push(Box::new(graphite::Graphite::new(graphite_host, *graphite_port)));
+        backends.push(Box::new(graphite::Graphite::new(graphite_host, *graphite_port)?));
     }
-    backends.into_boxed_slice()
+    Ok(backends.into_boxed_slice())
 }
 
 
@@ -37,19 +48,25 @@ mod test {
 
     #[test]
     fn factory_makes_graphite() {
-        let backends = factory(&false, &true, "127.0.0.1", &2300);
+        let backends = factory(&false, &true, "127.0.0.1", &2300).unwrap();
         assert_eq!(1, backends.len());
     }
 
     #[test]
     fn factory_makes_console() {
-        let backends = factory(&true, &false, "127.0.0.1", &2300);
+        let backends = factory(&true, &false, "127.0.0.1", &2300).unwrap();
         assert_eq!(1, backends.len());
     }
 
     #[test]
     fn factory_makes_both() {
-        let backends = factory(&true, &true, "127.0.0.1", &2300);
+        let backends = factory(&true, &true, "127.0.0.1", &2300).unwrap();
         assert_eq!(2, backends.len());
     }
+
+    #[test]
+    fn factory_invalid_graphite_host() {
+        assert!(factory(&false, &true, "not a host", &2300).is_err());
+        assert!(factory(&true, &false, "not a host", &2300).is_ok());
+    }
 }
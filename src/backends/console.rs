@@ -1,5 +1,7 @@
 use super::super::backend::Backend;
 use super::super::buckets::Buckets;
+use super::super::events::{Event, ServiceCheck};
+use super::super::metric::MetricKey;
 use time;
 
 #[derive(Debug)]
@@ -12,6 +14,8 @@ impl Console {
     /// # Examples
     ///
     /// ```
+    /// use statsd::backends::console::Console;
+    ///
     /// let cons = Console::new();
     /// ```
     pub fn new() -> Console {
@@ -19,28 +23,51 @@ impl Console {
     }
 }
 
+impl Default for Console {
+    fn default() -> Console {
+        Console::new()
+    }
+}
+
 /// Print a single stats line.
-fn fmt_line(key: &str, value: &f64) {
+fn fmt_line(key: &MetricKey, value: &f64) {
     println!("    {}: {}", key, value)
 }
 
 
 impl Backend for Console {
     fn flush_buckets(&mut self, buckets: &Buckets) {
-        let now = time::get_time();
-        println!("Flushing metrics: {}", time::at(now).rfc822().to_string());
+        let now = time::Timespec::new(buckets.flush_timestamp(), 0);
+        println!("Flushing metrics: {}", time::at(now).rfc822());
 
         println!("  bad_messages: {}", buckets.bad_messages());
         println!("  total_messages: {}", buckets.total_messages());
+        println!("  truncated_packets: {}", buckets.truncated_packets());
+        println!("  dropped_packets: {}", buckets.dropped_packets());
+        println!("  queue_peak_depth: {}", buckets.queue_peak_depth());
+        println!("  flush_lateness_ms: {}", buckets.flush_lateness().as_millis());
 
         println!("  counters:");
         for (key, value) in buckets.counters().iter() {
-            fmt_line(&key, &value);
+            fmt_line(key, value);
         }
 
         println!("  gauges:");
         for (key, value) in buckets.gauges().iter() {
-            fmt_line(&key, &value);
+            fmt_line(key, value);
+        }
+
+        println!("  timestamped:");
+        for (&(ref key, timestamp), value) in buckets.timestamped_counters().iter() {
+            println!("    {} @{}: {}", key, timestamp, value);
+        }
+        for (&(ref key, timestamp), value) in buckets.timestamped_gauges().iter() {
+            println!("    {} @{}: {}", key, timestamp, value);
+        }
+
+        println!("  sets:");
+        for (key, values) in buckets.sets().iter() {
+            println!("    {}: {}", key, values.len());
         }
 
         println!("  timers:");
@@ -52,5 +79,43 @@ impl Backend for Console {
         for (key, values) in buckets.timer_data().iter() {
             println!("    {}: {:?}", key, values);
         }
+
+        println!("  histograms:");
+        for (key, values) in buckets.histograms().iter() {
+            println!("    {}: {:?}", key, values);
+        }
+
+        println!("  histogram_data:");
+        for (key, value) in buckets.histogram_data().iter() {
+            fmt_line(key, value);
+        }
+
+        println!("  distributions:");
+        for (key, values) in buckets.distributions().iter() {
+            println!("    {}: {:?}", key, values);
+        }
+
+        println!("  distribution_data:");
+        for (key, value) in buckets.distribution_data().iter() {
+            fmt_line(key, value);
+        }
+    }
+
+    fn flush_events(&mut self, events: &[Event], service_checks: &[ServiceCheck]) {
+        println!("  events:");
+        for event in events.iter() {
+            let key = MetricKey::new(event.title.as_str(), &event.tags);
+            let alert_type = event.alert_type.as_ref().map_or("info", |t| t.as_str());
+            println!("    {} ({}): {:?}", key, alert_type, event.text);
+        }
+
+        println!("  service_checks:");
+        for check in service_checks.iter() {
+            let key = MetricKey::new(check.name.as_str(), &check.tags);
+            match check.message {
+                Some(ref message) => println!("    {}: {} {:?}", key, check.status, message),
+                None => println!("    {}: {}", key, check.status),
+            }
+        }
     }
 }
@@ -1,99 +1,261 @@
 use super::super::backend::Backend;
 use super::super::buckets::Buckets;
-use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
-use std::str::FromStr;
+use super::super::events::{Event, ServiceCheck};
+use super::super::metric::MetricKey;
+use super::super::sanitize::clean_name;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::fmt::Write;
-use std::io::Write as IoWrite;
+use std::io::{self, Write as IoWrite};
 use time;
 
 
 #[derive(Debug)]
 pub struct Graphite {
-    addr: SocketAddrV4,
+    addr: SocketAddr,
+    // Kept for flush reporting, not read yet.
+    #[allow(dead_code)]
     last_flush_time: u64,
+    #[allow(dead_code)]
     last_flush_length: u64,
+    // The timestamp of the last flush, used for events without one.
+    flush_timestamp: Option<i64>,
+    // The connection metrics were sent on, events are sent after them.
+    stream: Option<TcpStream>,
 }
 
 
 impl Graphite {
     /// Create a Graphite formatter
     ///
+    /// The host can be an IP address or a hostname, which is resolved
+    /// once here. Returns an error if it can't be resolved.
+    ///
     /// # Examples
     ///
     /// ```
-    /// let graph = Graphite::new(host, port);
+    /// use statsd::backends::graphite::Graphite;
+    ///
+    /// let graph = Graphite::new("127.0.0.1", 2003).unwrap();
     /// ```
-    pub fn new(host: &str, port: u16) -> Graphite {
-        let ip = Ipv4Addr::from_str(&host).unwrap();
-        let addr = SocketAddrV4::new(ip, port);
-        Graphite {
-            addr: addr,
+    pub fn new(host: &str, port: u16) -> io::Result<Graphite> {
+        let addr = match (host, port).to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("No address found for {}", host)))
+            }
+        };
+        Ok(Graphite {
+            addr,
             last_flush_time: 0,
             last_flush_length: 0,
+            flush_timestamp: None,
+            stream: None,
+        })
+    }
+
+    /// Write to the connection for this flush, closing it on errors.
+    fn send(&mut self, stats: &str) {
+        let result = match self.stream {
+            Some(ref mut stream) => stream.write_all(stats.as_bytes()),
+            None => return,
+        };
+        if let Err(e) = result {
+            eprintln!("Unable to send to graphite at {}: {}", self.addr, e);
+            self.stream = None;
         }
     }
 
     /// Convert the buckets into a String that
     /// can be sent to graphite's newline API
     pub fn format_stats(&self, buckets: &Buckets) -> String {
-        let start = time::get_time().sec;
+        let start = buckets.flush_timestamp();
         let mut stats = String::new();
 
-        write!(stats,
-               "{} {} {}\n",
-               "statsd.bad_messages",
-               buckets.bad_messages(),
-               start)
+        writeln!(stats,
+                 "statsd.bad_messages {} {}",
+                 buckets.bad_messages(),
+                 start)
+            .unwrap();
+        writeln!(stats,
+                 "statsd.total_messages {} {}",
+                 buckets.total_messages(),
+                 start)
+            .unwrap();
+        writeln!(stats,
+                 "statsd.dropped_packets {} {}",
+                 buckets.dropped_packets(),
+                 start)
             .unwrap();
-        write!(stats,
-               "{} {} {}\n",
-               "statsd.total_messages",
-               buckets.total_messages(),
-               start)
+        writeln!(stats,
+                 "statsd.queue_peak_depth {} {}",
+                 buckets.queue_peak_depth(),
+                 start)
+            .unwrap();
+        writeln!(stats,
+                 "statsd.flush_lateness_ms {} {}",
+                 buckets.flush_lateness().as_millis(),
+                 start)
             .unwrap();
 
         for (key, value) in buckets.counters().iter() {
-            write!(stats, "{} {} {} \n", key, value, start).unwrap();
+            writeln!(stats, "{} {} {} ", graphite_key(key), value, start).unwrap();
         }
 
         for (key, value) in buckets.gauges().iter() {
-            write!(stats, "{} {} {} \n", key, value, start).unwrap();
+            writeln!(stats, "{} {} {} ", graphite_key(key), value, start).unwrap();
+        }
+
+        // Metrics with client supplied timestamps keep their timestamp.
+        for (&(ref key, timestamp), value) in buckets.timestamped_counters().iter() {
+            writeln!(stats, "{} {} {} ", graphite_key(key), value, timestamp).unwrap();
+        }
+        for (&(ref key, timestamp), value) in buckets.timestamped_gauges().iter() {
+            writeln!(stats, "{} {} {} ", graphite_key(key), value, timestamp).unwrap();
+        }
+
+        // Sets are reported by their cardinality.
+        for (key, values) in buckets.sets().iter() {
+            writeln!(stats,
+                     "{} {} {} ",
+                     graphite_key(&key.suffixed("count")),
+                     values.len(),
+                     start)
+                .unwrap();
         }
 
         // The raw timer data is not sent to graphite.
         for (key, value) in buckets.timer_data().iter() {
-            write!(stats, "{} {} {} \n", key, value, start).unwrap();
+            writeln!(stats, "{} {} {} ", graphite_key(key), value, start).unwrap();
+        }
+
+        // Histograms and distributions are namespaced so they
+        // can't be confused with millisecond timers.
+        for (key, value) in buckets.histogram_data().iter() {
+            writeln!(stats, "histograms.{} {} {} ", graphite_key(key), value, start).unwrap();
+        }
+        for (key, value) in buckets.distribution_data().iter() {
+            writeln!(stats, "distributions.{} {} {} ", graphite_key(key), value, start).unwrap();
+        }
+        stats
+    }
+
+    /// Convert events and service checks into graphite's newline API.
+    ///
+    /// Graphite has no plaintext format for events, so each event is
+    /// sent as `events.<title>` with a value of 1 at the event's time.
+    /// The alert type is added as a tag. Service checks are sent as
+    /// `service_checks.<name>` with the numeric status as their value.
+    /// Those without a timestamp use the time of the last flush.
+    /// Titles, names and tags are sanitized, and events or checks
+    /// whose name is empty afterwards are skipped.
+    pub fn format_events(&self, events: &[Event], service_checks: &[ServiceCheck]) -> String {
+        let start = self.flush_timestamp.unwrap_or_else(|| time::get_time().sec);
+        let mut stats = String::new();
+
+        for event in events.iter() {
+            let title = clean_name(&event.title);
+            if title.is_empty() {
+                continue;
+            }
+            let mut tags = clean_tags(&event.tags);
+            if let Some(ref alert_type) = event.alert_type {
+                tags.push(("alert_type".to_string(), clean_name(alert_type)));
+            }
+            let key = MetricKey::new(format!("events.{}", title), &tags);
+            writeln!(stats,
+                     "{} 1 {} ",
+                     graphite_key(&key),
+                     event.timestamp.unwrap_or(start))
+                .unwrap();
+        }
+
+        for check in service_checks.iter() {
+            let name = clean_name(&check.name);
+            if name.is_empty() {
+                continue;
+            }
+            let key = MetricKey::new(format!("service_checks.{}", name),
+                                     &clean_tags(&check.tags));
+            writeln!(stats,
+                     "{} {} {} ",
+                     graphite_key(&key),
+                     check.status as u8,
+                     check.timestamp.unwrap_or(start))
+                .unwrap();
         }
         stats
     }
 }
 
 
+/// Sanitize tags, dropping those without a key.
+fn clean_tags(tags: &[(String, String)]) -> Vec<(String, String)> {
+    tags.iter()
+        .map(|(key, value)| (clean_name(key), clean_name(value)))
+        .filter(|tag| !tag.0.is_empty())
+        .collect()
+}
+
+
+/// Render a key as a graphite path.
+///
+/// Tagged keys use graphite's tagged series format,
+/// e.g. `name;env=prod`. Tags without a value are sent as `tag=true`
+/// as graphite requires every tag to have a value.
+fn graphite_key(key: &MetricKey) -> String {
+    let mut path = key.name.clone();
+    for (name, value) in key.tags.iter() {
+        let value = if value.is_empty() { "true" } else { value };
+        write!(path, ";{}={}", name, value).unwrap();
+    }
+    path
+}
+
+
 impl Backend for Graphite {
     fn flush_buckets(&mut self, buckets: &Buckets) {
-        let stats = self.format_stats(&buckets);
+        self.flush_timestamp = Some(buckets.flush_timestamp());
+        let stats = self.format_stats(buckets);
+
+        self.stream = match TcpStream::connect(self.addr) {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                eprintln!("Unable to connect to graphite at {}: {}", self.addr, e);
+                None
+            }
+        };
+        self.send(&stats);
+    }
 
-        let mut stream = TcpStream::connect(self.addr).unwrap();
-        let _ = stream.write(stats.as_bytes());
+    fn flush_events(&mut self, events: &[Event], service_checks: &[ServiceCheck]) {
+        if !events.is_empty() || !service_checks.is_empty() {
+            let stats = self.format_events(events, service_checks);
+            self.send(&stats);
+        }
+        // Close the connection until the next flush.
+        self.stream = None;
     }
 }
 
 
 #[cfg(test)]
 mod test {
+    use super::super::super::events::{CheckStatus, Event, ServiceCheck};
     use super::super::super::metric::{Metric, MetricKind};
     use super::super::super::buckets::Buckets;
     use super::super::super::metric_processor::process;
     use super::*;
+    use std::time::Duration;
 
     fn make_buckets() -> Buckets {
         let mut buckets = Buckets::new();
         let m1 = Metric::new("test.counter", 1.0, MetricKind::Counter(1.0));
         let m2 = Metric::new("test.gauge", 3.211, MetricKind::Gauge);
 
-        let m3 = Metric::new("test.timer", 12.101, MetricKind::Timer);
-        let m4 = Metric::new("test.timer", 1.101, MetricKind::Timer);
-        let m5 = Metric::new("test.timer", 3.101, MetricKind::Timer);
+        let m3 = Metric::new("test.timer", 12.101, MetricKind::Timer(1.0));
+        let m4 = Metric::new("test.timer", 1.101, MetricKind::Timer(1.0));
+        let m5 = Metric::new("test.timer", 3.101, MetricKind::Timer(1.0));
         buckets.add(&m1);
         buckets.add(&m2);
         buckets.add(&m3);
@@ -102,18 +264,28 @@ mod test {
         buckets
     }
 
+    #[test]
+    fn test_new_resolves_host() {
+        assert!(Graphite::new("::1", 2003).is_ok());
+        assert!(Graphite::new("localhost", 2003).is_ok());
+        assert!(Graphite::new("not a host", 2003).is_err());
+    }
+
     #[test]
     fn test_format_buckets_no_timers() {
         let buckets = make_buckets();
-        let graphite = Graphite::new("127.0.0.1", 2003);
+        let graphite = Graphite::new("127.0.0.1", 2003).unwrap();
         let result = graphite.format_stats(&buckets);
         let lines: Vec<&str> = result.lines().collect();
 
-        assert_eq!(4, lines.len());
+        assert_eq!(7, lines.len());
         assert!(lines[0].contains("statsd.bad_messages 0"));
         assert!(lines[1].contains("statsd.total_messages 5"));
-        assert!(lines[2].contains("test.counter 1"));
-        assert!(lines[3].contains("test.gauge 3.211"));
+        assert!(lines[2].contains("statsd.dropped_packets 0"));
+        assert!(lines[3].contains("statsd.queue_peak_depth 0"));
+        assert!(lines[4].contains("statsd.flush_lateness_ms 0"));
+        assert!(lines[5].contains("test.counter 1"));
+        assert!(lines[6].contains("test.gauge 3.211"));
     }
 
     #[test]
@@ -121,14 +293,153 @@ mod test {
         let mut buckets = make_buckets();
         process(&mut buckets);
 
-        let graphite = Graphite::new("127.0.0.1", 2003);
+        let graphite = Graphite::new("127.0.0.1", 2003).unwrap();
         let result = graphite.format_stats(&buckets);
         let lines: Vec<&str> = result.lines().collect();
 
-        assert_eq!(12, lines.len());
+        assert_eq!(16, lines.len());
 
         assert!(result.contains("test.timer.max 12.101"));
         assert!(result.contains("test.timer.min 1.101"));
         assert!(result.contains("test.timer.count 3"));
     }
+
+    #[test]
+    fn test_format_buckets_tags() {
+        let mut buckets = Buckets::new();
+        let tags = vec![("region".to_string(), "eu".to_string()),
+                        ("env".to_string(), "prod".to_string()),
+                        ("canary".to_string(), "".to_string())];
+        buckets.add(&Metric::new("test.counter", 1.0, MetricKind::Counter(1.0))
+                        .with_tags(tags.clone()));
+        buckets.add(&Metric::new("test.timer", 1.0, MetricKind::Timer(1.0)).with_tags(tags));
+        process(&mut buckets);
+
+        let graphite = Graphite::new("127.0.0.1", 2003).unwrap();
+        let result = graphite.format_stats(&buckets);
+
+        assert!(result.contains("test.counter;canary=true;env=prod;region=eu 1"));
+        assert!(result.contains("test.timer.max;canary=true;env=prod;region=eu 1"));
+    }
+
+    #[test]
+    fn test_format_buckets_histograms() {
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("test.size", 12.0, MetricKind::Histogram(1.0)));
+        buckets.add(&Metric::new("test.size", 3.0, MetricKind::Distribution(1.0)));
+        process(&mut buckets);
+
+        let graphite = Graphite::new("127.0.0.1", 2003).unwrap();
+        let result = graphite.format_stats(&buckets);
+
+        assert!(result.contains("histograms.test.size.max 12"));
+        assert!(result.contains("distributions.test.size.max 3"));
+        assert!(!result.contains("\ntest.size.max"));
+    }
+
+    #[test]
+    fn test_format_buckets_timestamped() {
+        let mut buckets = Buckets::new();
+        let ts = time::get_time().sec - 300;
+        buckets.add(&Metric::new("test.counter", 2.0, MetricKind::Counter(1.0)).with_timestamp(ts));
+        buckets.add(&Metric::new("test.gauge", 7.0, MetricKind::Gauge).with_timestamp(ts));
+
+        let graphite = Graphite::new("127.0.0.1", 2003).unwrap();
+        let result = graphite.format_stats(&buckets);
+
+        assert!(result.contains(&format!("test.counter 2 {} \n", ts)));
+        assert!(result.contains(&format!("test.gauge 7 {} \n", ts)));
+    }
+
+    #[test]
+    fn test_format_buckets_flush_time() {
+        let mut buckets = make_buckets();
+        buckets.set_flush_time(1700000010, Duration::from_millis(1500));
+
+        let graphite = Graphite::new("127.0.0.1", 2003).unwrap();
+        let result = graphite.format_stats(&buckets);
+
+        assert!(result.contains("statsd.flush_lateness_ms 1500 1700000010\n"));
+        assert!(result.lines().all(|line| line.trim_end().ends_with(" 1700000010")));
+    }
+
+    #[test]
+    fn test_format_events() {
+        let mut event = Event::new("Deploy v1.2", "Shipped");
+        event.timestamp = Some(1700000000);
+        event.alert_type = Some("success".to_string());
+        event.tags = vec![("env".to_string(), "prod".to_string())];
+        let mut check = ServiceCheck::new("db.up", CheckStatus::Critical);
+        check.timestamp = Some(1700000001);
+
+        let graphite = Graphite::new("127.0.0.1", 2003).unwrap();
+        let result = graphite.format_events(&[event], &[check]);
+        let lines: Vec<&str> = result.lines().collect();
+
+        assert_eq!(2, lines.len());
+        assert_eq!("events.Deploy_v1.2;alert_type=success;env=prod 1 1700000000 ", lines[0]);
+        assert_eq!("service_checks.db.up 2 1700000001 ", lines[1]);
+    }
+
+    #[test]
+    fn test_format_events_sanitized() {
+        let mut event = Event::new("Deploy;v1", "Shipped");
+        event.timestamp = Some(1700000000);
+        event.alert_type = Some("a=b".to_string());
+        event.tags = vec![("env name".to_string(), "prod;x".to_string()),
+                          ("!!".to_string(), "dropped".to_string())];
+        let mut empty = Event::new("!!!", "No title");
+        empty.timestamp = Some(1700000000);
+
+        let graphite = Graphite::new("127.0.0.1", 2003).unwrap();
+        let result = graphite.format_events(&[event, empty], &[]);
+
+        assert_eq!("events.Deployv1;alert_type=ab;env_name=prodx 1 1700000000 \n", result);
+    }
+
+    #[test]
+    fn test_flush_one_connection() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut graphite = Graphite::new("127.0.0.1", port).unwrap();
+        let mut event = Event::new("deploy", "Shipped");
+        event.timestamp = Some(1700000000);
+
+        graphite.flush_buckets(&make_buckets());
+        graphite.flush_events(&[event], &[]);
+
+        let mut out = String::new();
+        listener.accept().unwrap().0.read_to_string(&mut out).unwrap();
+        assert!(out.contains("test.counter 1"));
+        assert!(out.ends_with("events.deploy 1 1700000000 \n"));
+    }
+
+    #[test]
+    fn test_flush_unreachable() {
+        let port = {
+            let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut graphite = Graphite::new("127.0.0.1", port).unwrap();
+        graphite.flush_buckets(&make_buckets());
+        graphite.flush_events(&[Event::new("deploy", "Shipped")], &[]);
+    }
+
+    #[test]
+    fn test_format_buckets_sets() {
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("test.set", 0.0, MetricKind::Set("a".to_string())));
+        buckets.add(&Metric::new("test.set", 0.0, MetricKind::Set("b".to_string())));
+        buckets.add(&Metric::new("test.set", 0.0, MetricKind::Set("a".to_string())));
+
+        let graphite = Graphite::new("127.0.0.1", 2003).unwrap();
+        let result = graphite.format_stats(&buckets);
+        let lines: Vec<&str> = result.lines().collect();
+
+        assert_eq!(6, lines.len());
+        assert!(lines[5].contains("test.set.count 2"));
+    }
 }
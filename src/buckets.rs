@@ -3,24 +3,120 @@
 //! Each bucket contains a set of hashmaps containing
 //! each set of metrics received by clients.
 
-use std::collections::HashMap;
-use super::metric::{Metric, MetricKind};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+use super::events::{Event, Message, ParsedPacket, ServiceCheck};
+use super::metric::{Metric, MetricKey, MetricKind};
 use super::metric_processor;
+use super::queue::QueueStats;
+use super::sanitize::{Outcome, Sanitizer};
 use time;
 
 
+/// Counters for a connection sending metrics over a stream socket.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionStats {
+    pub peer: String,
+    pub bytes: usize,
+    pub messages: usize,
+    pub bad_messages: usize,
+}
+
+
+/// Whether a listener is receiving metrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListenerState {
+    Up,
+    // The socket failed and is being bound again.
+    Restarting,
+    // The socket could not be bound again.
+    Failed,
+}
+
+impl fmt::Display for ListenerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match *self {
+            ListenerState::Up => "up",
+            ListenerState::Restarting => "restarting",
+            ListenerState::Failed => "failed",
+        };
+        f.write_str(state)
+    }
+}
+
+/// Health of a listening socket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerStats {
+    pub state: ListenerState,
+    pub restarts: usize,
+    pub errors: usize,
+    pub last_error: Option<String>,
+}
+
+impl Default for ListenerStats {
+    fn default() -> ListenerStats {
+        ListenerStats {
+            state: ListenerState::Up,
+            restarts: 0,
+            errors: 0,
+            last_error: None,
+        }
+    }
+}
+
+
 /// Buckets stores all metrics until they are flushed.
+///
+/// Metrics are aggregated on their name and tags, see `MetricKey`.
+#[derive(Clone)]
 pub struct Buckets {
-    counters: HashMap<String, f64>,
-    gauges: HashMap<String, f64>,
-    timers: HashMap<String, Vec<f64>>,
+    counters: HashMap<MetricKey, f64>,
+    gauges: HashMap<MetricKey, f64>,
+    timers: HashMap<MetricKey, Vec<f64>>,
+    timer_counts: HashMap<MetricKey, f64>,
+    histograms: HashMap<MetricKey, Vec<f64>>,
+    histogram_counts: HashMap<MetricKey, f64>,
+    distributions: HashMap<MetricKey, Vec<f64>>,
+    distribution_counts: HashMap<MetricKey, f64>,
+    sets: HashMap<MetricKey, HashSet<String>>,
+    events: Vec<Event>,
+    service_checks: Vec<ServiceCheck>,
 
-    timer_data: HashMap<String, f64>,
+    // Metrics with client supplied timestamps, keyed on name and timestamp.
+    timestamped_counters: HashMap<(MetricKey, i64), f64>,
+    timestamped_gauges: HashMap<(MetricKey, i64), f64>,
+    timestamp_window: i64,
+
+    timer_data: HashMap<MetricKey, f64>,
+    histogram_data: HashMap<MetricKey, f64>,
+    distribution_data: HashMap<MetricKey, f64>,
+    flush_interval: u64,
+    // Set by the server for the flush in progress.
+    flush_timestamp: Option<i64>,
+    flush_lateness: Duration,
+    sanitizer: Sanitizer,
 
     server_start_time: time::Timespec,
     last_message: time::Timespec,
     bad_messages: usize,
+    truncated_packets: usize,
+    queue_stats: QueueStats,
     total_messages: usize,
+    renamed_keys: usize,
+    rejected_keys: usize,
+
+    // Open stream connections, these are kept across flushes.
+    connections: BTreeMap<usize, ConnectionStats>,
+    rejected_connections: usize,
+
+    // Listeners by name, these are kept across flushes.
+    listeners: BTreeMap<String, ListenerStats>,
+
+    // Configuration reloads, these are kept across flushes.
+    reloads: usize,
+    failed_reloads: usize,
+    last_reload_error: Option<String>,
 }
 
 impl Buckets {
@@ -30,6 +126,8 @@ impl Buckets {
     /// # Examples
     ///
     /// ```
+    /// use statsd::buckets::Buckets;
+    ///
     /// let bucket = Buckets::new();
     /// assert_eq!(0, bucket.counters().len());
     /// ```
@@ -38,9 +136,36 @@ impl Buckets {
             counters: HashMap::new(),
             gauges: HashMap::new(),
             timers: HashMap::new(),
+            timer_counts: HashMap::new(),
+            histograms: HashMap::new(),
+            histogram_counts: HashMap::new(),
+            distributions: HashMap::new(),
+            distribution_counts: HashMap::new(),
+            sets: HashMap::new(),
+            events: Vec::new(),
+            service_checks: Vec::new(),
+            timestamped_counters: HashMap::new(),
+            timestamped_gauges: HashMap::new(),
+            timestamp_window: 3600,
             timer_data: HashMap::new(),
+            histogram_data: HashMap::new(),
+            distribution_data: HashMap::new(),
+            flush_interval: 10,
+            flush_timestamp: None,
+            flush_lateness: Duration::new(0, 0),
+            sanitizer: Sanitizer::default(),
             bad_messages: 0,
+            truncated_packets: 0,
+            queue_stats: QueueStats::default(),
             total_messages: 0,
+            renamed_keys: 0,
+            rejected_keys: 0,
+            connections: BTreeMap::new(),
+            rejected_connections: 0,
+            listeners: BTreeMap::new(),
+            reloads: 0,
+            failed_reloads: 0,
+            last_reload_error: None,
             last_message: time::get_time(),
             server_start_time: time::get_time(),
         }
@@ -51,33 +176,244 @@ impl Buckets {
     /// # Examples
     ///
     /// ```
-    /// use buckets::Buckets;
-    /// use super::metric;
-    /// use std::str::FromStr;
+    /// use statsd::buckets::Buckets;
+    /// use statsd::metric::{Metric, MetricKind};
     ///
-    /// let metric = metric::Metric::FromStr("foo:1|c");
+    /// let metric = Metric::new("foo", 1.0, MetricKind::Counter(1.0));
     /// let mut bucket = Buckets::new();
-    /// bucket.add(metric);
+    /// bucket.add(&metric);
     /// ```
     pub fn add(&mut self, value: &Metric) {
-        let name = value.name.to_owned();
+        if let Some(timestamp) = value.timestamp {
+            return self.add_timestamped(value, timestamp);
+        }
+        let name = value.key();
         match value.kind {
             MetricKind::Counter(rate) => {
                 let counter = self.counters.entry(name).or_insert(0.0);
-                *counter = *counter + value.value * (1.0 / rate);
+                *counter += value.value * (1.0 / rate);
             }
             MetricKind::Gauge => {
                 self.gauges.insert(name, value.value);
             }
-            MetricKind::Timer => {
-                let slot = self.timers.entry(name).or_insert(Vec::new());
-                slot.push(value.value);
+            MetricKind::GaugeDelta => {
+                let gauge = self.gauges.entry(name).or_insert(0.0);
+                *gauge += value.value;
+            }
+            MetricKind::Timer(rate) => {
+                add_sampled(&mut self.timers, &mut self.timer_counts, name, value.value, rate);
+            }
+            MetricKind::Histogram(rate) => {
+                add_sampled(&mut self.histograms,
+                            &mut self.histogram_counts,
+                            name,
+                            value.value,
+                            rate);
+            }
+            MetricKind::Distribution(rate) => {
+                add_sampled(&mut self.distributions,
+                            &mut self.distribution_counts,
+                            name,
+                            value.value,
+                            rate);
+            }
+            MetricKind::Set(ref member) => {
+                let slot = self.sets.entry(name).or_default();
+                slot.insert(member.to_owned());
+            }
+        }
+        self.last_message = time::get_time();
+        self.total_messages += 1;
+    }
+
+    /// Adds a metric with a client supplied timestamp.
+    ///
+    /// These are stored per timestamp instead of being aggregated with
+    /// the current interval. Metrics with timestamps further than the
+    /// timestamp window from the current time count as bad messages.
+    fn add_timestamped(&mut self, value: &Metric, timestamp: i64) {
+        let now = time::get_time().sec;
+        if (now - timestamp).abs() > self.timestamp_window {
+            return self.add_bad_message();
+        }
+        let name = (value.key(), timestamp);
+        match value.kind {
+            MetricKind::Counter(rate) => {
+                let counter = self.timestamped_counters.entry(name).or_insert(0.0);
+                *counter += value.value * (1.0 / rate);
+            }
+            MetricKind::Gauge => {
+                self.timestamped_gauges.insert(name, value.value);
+            }
+            MetricKind::GaugeDelta => {
+                // Deltas apply to the gauge's value at that time if it was
+                // sent, otherwise to its current value.
+                let current = self.gauges.get(&name.0).cloned().unwrap_or(0.0);
+                let gauge = self.timestamped_gauges.entry(name).or_insert(current);
+                *gauge += value.value;
             }
+            // Only counters and gauges can have timestamps.
+            _ => return self.add_bad_message(),
         }
         self.last_message = time::get_time();
         self.total_messages += 1;
     }
 
+    /// Adds an event to the bucket storage.
+    pub fn add_event(&mut self, event: Event) {
+        self.events.push(event);
+        self.last_message = time::get_time();
+        self.total_messages += 1;
+    }
+
+    /// Adds a service check to the bucket storage.
+    pub fn add_service_check(&mut self, check: ServiceCheck) {
+        self.service_checks.push(check);
+        self.last_message = time::get_time();
+        self.total_messages += 1;
+    }
+
+    /// Parses a raw packet and adds each metric, event and
+    /// service check in it.
+    ///
+    /// Valid lines are kept even when other lines in the packet are
+    /// malformed. Each bad line counts as a bad message, as does a packet
+    /// that is not valid UTF-8 or contains no metrics.
+    ///
+    /// Metric names are sanitized before they are added, metrics
+    /// rejected by the sanitizer count as bad messages.
+    pub fn add_packet(&mut self, packet: &[u8]) {
+        self.add_parsed(ParsedPacket::parse(packet, false));
+    }
+
+    /// Adds a packet that was cut short by the receive buffer.
+    ///
+    /// The partial last line is dropped and counted as a bad message,
+    /// complete lines before it are added as usual.
+    pub fn add_truncated_packet(&mut self, packet: &[u8]) {
+        self.add_parsed(ParsedPacket::parse(packet, true));
+    }
+
+    /// Adds a packet that was already parsed, see `add_packet`.
+    pub fn add_parsed(&mut self, packet: ParsedPacket) {
+        if packet.truncated {
+            self.truncated_packets += 1;
+            self.add_bad_message();
+        }
+        if packet.invalid {
+            return self.add_bad_message();
+        }
+        for result in packet.messages {
+            let mut metric = match result {
+                Ok(Message::Metric(metric)) => metric,
+                Ok(Message::Event(event)) => {
+                    self.add_event(event);
+                    continue;
+                }
+                Ok(Message::ServiceCheck(check)) => {
+                    self.add_service_check(check);
+                    continue;
+                }
+                Err(_) => {
+                    self.add_bad_message();
+                    continue;
+                }
+            };
+            match self.sanitizer.sanitize(&mut metric) {
+                Outcome::Unchanged => self.add(&metric),
+                Outcome::Renamed => {
+                    self.renamed_keys += 1;
+                    self.add(&metric);
+                }
+                Outcome::Rejected => {
+                    self.rejected_keys += 1;
+                    self.add_bad_message();
+                }
+            }
+        }
+    }
+
+    /// Track a new stream connection.
+    pub fn add_connection(&mut self, id: usize, peer: String) {
+        let stats = ConnectionStats { peer, ..Default::default() };
+        self.connections.insert(id, stats);
+    }
+
+    /// Stop tracking a closed stream connection.
+    pub fn remove_connection(&mut self, id: usize) {
+        self.connections.remove(&id);
+    }
+
+    /// Get the open stream connections keyed by connection id.
+    pub fn connections(&self) -> &BTreeMap<usize, ConnectionStats> {
+        &self.connections
+    }
+
+    /// Count a stream connection closed because too many were open.
+    pub fn add_rejected_connection(&mut self) {
+        self.rejected_connections += 1;
+    }
+
+    /// Get the count of rejected stream connections since the server started.
+    pub fn rejected_connections(&self) -> usize {
+        self.rejected_connections
+    }
+
+    /// Track a listener that has started.
+    pub fn add_listener(&mut self, name: String) {
+        self.listeners.insert(name, ListenerStats::default());
+    }
+
+    /// Record an error from a listener that is being restarted.
+    pub fn add_listener_error(&mut self, name: &str, error: String) {
+        let stats = self.listeners.entry(name.to_string()).or_default();
+        stats.state = ListenerState::Restarting;
+        stats.errors += 1;
+        stats.last_error = Some(error);
+    }
+
+    /// Record that a listener was bound again after an error.
+    pub fn add_listener_restart(&mut self, name: &str) {
+        let stats = self.listeners.entry(name.to_string()).or_default();
+        stats.state = ListenerState::Up;
+        stats.restarts += 1;
+    }
+
+    /// Record that a listener could not be bound again.
+    pub fn add_listener_failure(&mut self, name: &str, error: String) {
+        let stats = self.listeners.entry(name.to_string()).or_default();
+        stats.state = ListenerState::Failed;
+        stats.last_error = Some(error);
+    }
+
+    /// Get the listeners keyed by name.
+    pub fn listeners(&self) -> &BTreeMap<String, ListenerStats> {
+        &self.listeners
+    }
+
+    /// Adds lines received on a stream connection.
+    ///
+    /// Behaves like `add_packet` and updates the connection's counters.
+    pub fn add_stream_packet(&mut self, id: usize, packet: &[u8]) {
+        let total = self.total_messages;
+        let bad = self.bad_messages;
+        self.add_packet(packet);
+        if let Some(stats) = self.connections.get_mut(&id) {
+            stats.bytes += packet.len();
+            stats.messages += self.total_messages - total;
+            stats.bad_messages += self.bad_messages - bad;
+        }
+    }
+
+    /// Count a line that was too long to buffer as a bad message.
+    pub fn add_stream_bad_line(&mut self, id: usize) {
+        self.add_bad_message();
+        if let Some(stats) = self.connections.get_mut(&id) {
+            stats.messages += 1;
+            stats.bad_messages += 1;
+        }
+    }
+
     /// Increment the bad message count by one.
     /// Also increments tht total message count.
     pub fn add_bad_message(&mut self) {
@@ -90,31 +426,197 @@ impl Buckets {
         self.bad_messages
     }
 
+    /// Get the count of packets that didn't fit in the receive buffer.
+    pub fn truncated_packets(&self) -> usize {
+        self.truncated_packets
+    }
+
+    /// Set the event queue counters for the current interval.
+    pub fn set_queue_stats(&mut self, stats: QueueStats) {
+        self.queue_stats = stats;
+    }
+
+    /// Get the count of packets dropped because the event queue was full.
+    pub fn dropped_packets(&self) -> usize {
+        self.queue_stats.dropped_packets
+    }
+
+    /// Get the most events that were waiting in the event queue.
+    pub fn queue_peak_depth(&self) -> usize {
+        self.queue_stats.peak_depth
+    }
+
+    /// Get the count of metrics renamed by sanitization.
+    pub fn renamed_keys(&self) -> usize {
+        self.renamed_keys
+    }
+
+    /// Get the count of metrics rejected by sanitization.
+    pub fn rejected_keys(&self) -> usize {
+        self.rejected_keys
+    }
+
+    /// Record a configuration reload and the error if it failed.
+    pub fn add_reload(&mut self, error: Option<String>) {
+        self.reloads += 1;
+        if error.is_some() {
+            self.failed_reloads += 1;
+        }
+        self.last_reload_error = error;
+    }
+
+    /// Get the count of configuration reloads, including failed ones.
+    pub fn reloads(&self) -> usize {
+        self.reloads
+    }
+
+    /// Get the count of configuration reloads that failed.
+    pub fn failed_reloads(&self) -> usize {
+        self.failed_reloads
+    }
+
+    /// Get the error from the last reload, if it failed.
+    pub fn last_reload_error(&self) -> Option<&str> {
+        self.last_reload_error.as_deref()
+    }
+
+    /// Replace the sanitizer applied to metrics in `add_packet`.
+    pub fn set_sanitizer(&mut self, sanitizer: Sanitizer) {
+        self.sanitizer = sanitizer;
+    }
+
     /// Get the counters as a borrowed reference.
-    pub fn counters(&self) -> &HashMap<String, f64> {
+    pub fn counters(&self) -> &HashMap<MetricKey, f64> {
         &self.counters
     }
 
     /// Get the gauges as a borrowed reference.
-    pub fn gauges(&self) -> &HashMap<String, f64> {
+    pub fn gauges(&self) -> &HashMap<MetricKey, f64> {
         &self.gauges
     }
 
     /// Get the timers as a borrowed reference.
-    pub fn timers(&self) -> &HashMap<String, Vec<f64>> {
+    pub fn timers(&self) -> &HashMap<MetricKey, Vec<f64>> {
         &self.timers
     }
 
+    /// Get the sample rate adjusted timer counts as a borrowed reference.
+    pub fn timer_counts(&self) -> &HashMap<MetricKey, f64> {
+        &self.timer_counts
+    }
+
+    /// Get the histograms as a borrowed reference.
+    pub fn histograms(&self) -> &HashMap<MetricKey, Vec<f64>> {
+        &self.histograms
+    }
+
+    /// Get the sample rate adjusted histogram counts as a borrowed reference.
+    pub fn histogram_counts(&self) -> &HashMap<MetricKey, f64> {
+        &self.histogram_counts
+    }
+
+    /// Get the distributions as a borrowed reference.
+    pub fn distributions(&self) -> &HashMap<MetricKey, Vec<f64>> {
+        &self.distributions
+    }
+
+    /// Get the sample rate adjusted distribution counts as a borrowed reference.
+    pub fn distribution_counts(&self) -> &HashMap<MetricKey, f64> {
+        &self.distribution_counts
+    }
+
+    /// Get the sets as a borrowed reference.
+    pub fn sets(&self) -> &HashMap<MetricKey, HashSet<String>> {
+        &self.sets
+    }
+
+    /// Get the counters with client supplied timestamps.
+    pub fn timestamped_counters(&self) -> &HashMap<(MetricKey, i64), f64> {
+        &self.timestamped_counters
+    }
+
+    /// Get the gauges with client supplied timestamps.
+    pub fn timestamped_gauges(&self) -> &HashMap<(MetricKey, i64), f64> {
+        &self.timestamped_gauges
+    }
+
+    /// Set how far in seconds client supplied timestamps can be
+    /// from the current time before they are rejected.
+    pub fn set_timestamp_window(&mut self, seconds: u64) {
+        self.timestamp_window = seconds as i64;
+    }
+
+    /// Get the events received since the last flush.
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Get the service checks received since the last flush.
+    pub fn service_checks(&self) -> &[ServiceCheck] {
+        &self.service_checks
+    }
+
     /// Get the calculated timer data as a borrowed reference.
-    pub fn timer_data(&self) -> &HashMap<String, f64> {
+    pub fn timer_data(&self) -> &HashMap<MetricKey, f64> {
         &self.timer_data
     }
 
     /// Replace the calculated timer data with a new hash map.
-    pub fn set_timer_data(&mut self, data: HashMap<String, f64>) {
+    pub fn set_timer_data(&mut self, data: HashMap<MetricKey, f64>) {
         self.timer_data = data;
     }
 
+    /// Get the calculated histogram data as a borrowed reference.
+    pub fn histogram_data(&self) -> &HashMap<MetricKey, f64> {
+        &self.histogram_data
+    }
+
+    /// Replace the calculated histogram data with a new hash map.
+    pub fn set_histogram_data(&mut self, data: HashMap<MetricKey, f64>) {
+        self.histogram_data = data;
+    }
+
+    /// Get the calculated distribution data as a borrowed reference.
+    pub fn distribution_data(&self) -> &HashMap<MetricKey, f64> {
+        &self.distribution_data
+    }
+
+    /// Replace the calculated distribution data with a new hash map.
+    pub fn set_distribution_data(&mut self, data: HashMap<MetricKey, f64>) {
+        self.distribution_data = data;
+    }
+
+    /// Get the flush interval in seconds.
+    pub fn flush_interval(&self) -> u64 {
+        self.flush_interval
+    }
+
+    /// Set the flush interval in seconds, used for per second rates.
+    pub fn set_flush_interval(&mut self, seconds: u64) {
+        self.flush_interval = seconds;
+    }
+
+    /// Set the time of the flush in progress, and how long after
+    /// it was due the flush started.
+    pub fn set_flush_time(&mut self, timestamp: i64, lateness: Duration) {
+        self.flush_timestamp = Some(timestamp);
+        self.flush_lateness = lateness;
+    }
+
+    /// Get the timestamp in seconds that backends should use for this flush.
+    ///
+    /// Every backend uses the same timestamp, which falls on a multiple
+    /// of the flush interval for flushes started by the server's timer.
+    /// Defaults to the current time.
+    pub fn flush_timestamp(&self) -> i64 {
+        self.flush_timestamp.unwrap_or_else(|| time::get_time().sec)
+    }
+
+    /// Get how long after it was due the most recent timed flush started.
+    pub fn flush_lateness(&self) -> Duration {
+        self.flush_lateness
+    }
+
     /// Get the total number of messages this bucket has seen
     /// (includes bad messages).
     pub fn total_messages(&self) -> usize {
@@ -126,18 +628,40 @@ impl Buckets {
         self.server_start_time
     }
 
-    /// Resets the counters and timers to 0.
+    /// Resets the counters, timers, histograms and distributions
+    /// to 0 and empties sets, events and service checks.
     /// Gauge values are preserved. This emulates the
     /// behavior of etsy/statsd with default configuration options.
     pub fn reset(&mut self) {
         for (_, value) in self.counters.iter_mut() {
             *value = 0.0;
         }
-        for (_, value) in self.timers.iter_mut() {
-            *value = Vec::new();
+        for values in [&mut self.timers, &mut self.histograms, &mut self.distributions] {
+            for (_, value) in values.iter_mut() {
+                *value = Vec::new();
+            }
+        }
+        for counts in [&mut self.timer_counts,
+                       &mut self.histogram_counts,
+                       &mut self.distribution_counts] {
+            for (_, value) in counts.iter_mut() {
+                *value = 0.0;
+            }
+        }
+        for (_, value) in self.sets.iter_mut() {
+            value.clear();
         }
+        self.events.clear();
+        self.service_checks.clear();
+        self.timestamped_counters.clear();
+        self.timestamped_gauges.clear();
         self.bad_messages = 0;
+        self.truncated_packets = 0;
+        self.queue_stats = QueueStats::default();
+        self.flush_timestamp = None;
         self.total_messages = 0;
+        self.renamed_keys = 0;
+        self.rejected_keys = 0;
     }
 
     /// Processes metrics adding in derived values.
@@ -146,15 +670,39 @@ impl Buckets {
     }
 }
 
+impl Default for Buckets {
+    fn default() -> Buckets {
+        Buckets::new()
+    }
+}
+
+
+/// Add a sampled value to a timer like series.
+///
+/// The count is scaled by the sample rate.
+fn add_sampled(values: &mut HashMap<MetricKey, Vec<f64>>,
+               counts: &mut HashMap<MetricKey, f64>,
+               name: MetricKey,
+               value: f64,
+               rate: f64) {
+    let count = counts.entry(name.clone()).or_insert(0.0);
+    *count += 1.0 / rate;
+    values.entry(name).or_default().push(value);
+}
+
 
 // Tests
 //
 #[cfg(test)]
 mod test {
     use super::*;
-    use super::super::metric::{Metric, MetricKind};
+    use super::super::metric::{Metric, MetricKey, MetricKind};
     use time;
 
+    fn key(name: &str) -> MetricKey {
+        MetricKey::from(name)
+    }
+
     #[test]
     fn test_bad_messages() {
         let mut buckets = Buckets::new();
@@ -167,6 +715,117 @@ mod test {
         assert_eq!(2, buckets.total_messages());
     }
 
+    #[test]
+    fn test_add_stream_packet() {
+        let mut buckets = Buckets::new();
+        buckets.add_connection(1, "127.0.0.1:1234".to_string());
+        buckets.add_stream_packet(1, b"foo:1|c\nbad\n");
+        buckets.add_stream_bad_line(1);
+        assert_eq!(Some(&1.0), buckets.counters().get(&key("foo")));
+
+        let stats = &buckets.connections()[&1];
+        assert_eq!("127.0.0.1:1234", stats.peer);
+        assert_eq!(12, stats.bytes);
+        assert_eq!(3, stats.messages);
+        assert_eq!(2, stats.bad_messages);
+
+        // Connection counters are kept across flushes.
+        buckets.reset();
+        assert_eq!(3, buckets.connections()[&1].messages);
+
+        buckets.remove_connection(1);
+        assert!(buckets.connections().is_empty());
+
+        buckets.add_rejected_connection();
+        buckets.reset();
+        assert_eq!(1, buckets.rejected_connections());
+    }
+
+    #[test]
+    fn test_add_truncated_packet() {
+        let mut buckets = Buckets::new();
+        buckets.add_truncated_packet(b"foo:1|c\nbar:2|c\nbaz:3");
+        assert_eq!(1, buckets.truncated_packets());
+        assert_eq!(1, buckets.bad_messages());
+        assert_eq!(3, buckets.total_messages());
+        assert_eq!(Some(&1.0), buckets.counters().get(&key("foo")));
+        assert_eq!(Some(&2.0), buckets.counters().get(&key("bar")));
+        assert_eq!(None, buckets.counters().get(&key("baz")));
+
+        buckets.add_truncated_packet(b"foo:1");
+        assert_eq!(2, buckets.truncated_packets());
+        assert_eq!(2, buckets.bad_messages());
+
+        buckets.reset();
+        assert_eq!(0, buckets.truncated_packets());
+    }
+
+    #[test]
+    fn test_add_packet() {
+        let mut buckets = Buckets::new();
+        buckets.add_packet(b"some.metric:1|c\nbad:line|c\nsome.metric:2|c\nother:1|x");
+        assert_eq!(Some(&3.0), buckets.counters.get(&key("some.metric")));
+        assert_eq!(2, buckets.bad_messages());
+        assert_eq!(4, buckets.total_messages());
+    }
+
+    #[test]
+    fn test_add_packet_invalid_utf8() {
+        let mut buckets = Buckets::new();
+        buckets.add_packet(b"some.metric:1|c\n\xff\xfe:1|c");
+        assert_eq!(0, buckets.counters().len());
+        assert_eq!(1, buckets.bad_messages());
+        assert_eq!(1, buckets.total_messages());
+    }
+
+    #[test]
+    fn test_add_packet_empty() {
+        let mut buckets = Buckets::new();
+        buckets.add_packet(b"\n");
+        assert_eq!(1, buckets.bad_messages());
+    }
+
+    #[test]
+    fn test_add_packet_sanitizes_names() {
+        let mut buckets = Buckets::new();
+        buckets.add_packet(b"some metric:1|c\nsome/metric:1|c\nok:1|c\n!!:1|c");
+        assert_eq!(Some(&1.0), buckets.counters.get(&key("some_metric")));
+        assert_eq!(Some(&1.0), buckets.counters.get(&key("some-metric")));
+        assert_eq!(Some(&1.0), buckets.counters.get(&key("ok")));
+        assert_eq!(2, buckets.renamed_keys());
+        assert_eq!(1, buckets.rejected_keys());
+        assert_eq!(1, buckets.bad_messages());
+
+        buckets.reset();
+        assert_eq!(0, buckets.renamed_keys());
+        assert_eq!(0, buckets.rejected_keys());
+    }
+
+    #[test]
+    fn test_add_packet_max_key_length() {
+        let mut buckets = Buckets::new();
+        buckets.set_sanitizer(Sanitizer::new(false, 4));
+        buckets.add_packet(b"some metric:1|c\nok:1|c");
+        assert_eq!(1, buckets.counters().len());
+        assert_eq!(1, buckets.rejected_keys());
+    }
+
+    #[test]
+    fn test_add_packet_events() {
+        let mut buckets = Buckets::new();
+        buckets.add_packet(b"_e{6,4}:Deploy|v1.2\n_sc|db.up|0\nsome.metric:1|c\n_sc|db.up|9");
+        assert_eq!(1, buckets.events().len());
+        assert_eq!("Deploy", buckets.events()[0].title);
+        assert_eq!(1, buckets.service_checks().len());
+        assert_eq!(1, buckets.counters().len());
+        assert_eq!(1, buckets.bad_messages());
+        assert_eq!(4, buckets.total_messages());
+
+        buckets.reset();
+        assert_eq!(0, buckets.events().len());
+        assert_eq!(0, buckets.service_checks().len());
+    }
+
     #[test]
     fn test_add_increments_total_messages() {
         let mut buckets = Buckets::new();
@@ -198,13 +857,13 @@ mod test {
         let metric = Metric::new("some.metric", 1.0, MetricKind::Counter(1.0));
         buckets.add(&metric);
 
-        assert!(buckets.counters.contains_key("some.metric"),
+        assert!(buckets.counters.contains_key(&key("some.metric")),
                 "Should contain the metric key");
-        assert_eq!(Some(&1.0), buckets.counters.get("some.metric"));
+        assert_eq!(Some(&1.0), buckets.counters.get(&key("some.metric")));
 
         // Increment counter
         buckets.add(&metric);
-        assert_eq!(Some(&2.0), buckets.counters.get("some.metric"));
+        assert_eq!(Some(&2.0), buckets.counters.get(&key("some.metric")));
         assert_eq!(1, buckets.counters().len());
         assert_eq!(0, buckets.gauges().len());
     }
@@ -215,11 +874,11 @@ mod test {
         let metric = Metric::new("some.metric", 1.0, MetricKind::Counter(0.1));
 
         buckets.add(&metric);
-        assert_eq!(Some(&10.0), buckets.counters.get("some.metric"));
+        assert_eq!(Some(&10.0), buckets.counters.get(&key("some.metric")));
 
         let metric_two = Metric::new("some.metric", 1.0, MetricKind::Counter(0.5));
         buckets.add(&metric_two);
-        assert_eq!(Some(&12.0), buckets.counters.get("some.metric"));
+        assert_eq!(Some(&12.0), buckets.counters.get(&key("some.metric")));
     }
 
     #[test]
@@ -227,54 +886,247 @@ mod test {
         let mut buckets = Buckets::new();
         let metric = Metric::new("some.metric", 11.5, MetricKind::Gauge);
         buckets.add(&metric);
-        assert!(buckets.gauges.contains_key("some.metric"),
+        assert!(buckets.gauges.contains_key(&key("some.metric")),
                 "Should contain the metric key");
-        assert_eq!(Some(&11.5), buckets.gauges.get("some.metric"));
+        assert_eq!(Some(&11.5), buckets.gauges.get(&key("some.metric")));
         assert_eq!(1, buckets.gauges().len());
         assert_eq!(0, buckets.counters().len());
     }
 
+    #[test]
+    fn test_add_gauge_delta_metric() {
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("some.metric", 3.0, MetricKind::GaugeDelta));
+        assert_eq!(Some(&3.0), buckets.gauges.get(&key("some.metric")));
+
+        buckets.add(&Metric::new("some.metric", 10.0, MetricKind::Gauge));
+        buckets.add(&Metric::new("some.metric", -4.5, MetricKind::GaugeDelta));
+        assert_eq!(Some(&5.5), buckets.gauges.get(&key("some.metric")));
+
+        buckets.add(&Metric::new("some.metric", 2.0, MetricKind::GaugeDelta));
+        assert_eq!(Some(&7.5), buckets.gauges.get(&key("some.metric")));
+    }
+
+    #[test]
+    fn test_add_gauge_negative_absolute() {
+        // Negative gauges are set by zeroing the gauge then applying a delta.
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("some.metric", 8.0, MetricKind::Gauge));
+        for metric in Metric::parse("some.metric:0|g\nsome.metric:-10|g").unwrap().iter() {
+            buckets.add(metric);
+        }
+        assert_eq!(Some(&-10.0), buckets.gauges.get(&key("some.metric")));
+    }
+
+    #[test]
+    fn test_gauge_delta_survives_reset() {
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("some.metric", 4.0, MetricKind::Gauge));
+        buckets.reset();
+        buckets.add(&Metric::new("some.metric", 1.0, MetricKind::GaugeDelta));
+        assert_eq!(Some(&5.0), buckets.gauges.get(&key("some.metric")));
+    }
+
     #[test]
     fn test_add_timer_metric() {
         let mut buckets = Buckets::new();
-        let metric = Metric::new("some.metric", 11.5, MetricKind::Timer);
+        let metric = Metric::new("some.metric", 11.5, MetricKind::Timer(1.0));
         buckets.add(&metric);
-        assert!(buckets.timers.contains_key("some.metric"),
+        assert!(buckets.timers.contains_key(&key("some.metric")),
                 "Should contain the metric key");
-        assert_eq!(Some(&vec![11.5]), buckets.timers.get("some.metric"));
+        assert_eq!(Some(&vec![11.5]), buckets.timers.get(&key("some.metric")));
 
-        let metric_two = Metric::new("some.metric", 99.5, MetricKind::Timer);
+        let metric_two = Metric::new("some.metric", 99.5, MetricKind::Timer(1.0));
         buckets.add(&metric_two);
 
-        let metric_three = Metric::new("other.metric", 811.5, MetricKind::Timer);
+        let metric_three = Metric::new("other.metric", 811.5, MetricKind::Timer(1.0));
         buckets.add(&metric_three);
-        assert!(buckets.timers.contains_key("some.metric"),
+        assert!(buckets.timers.contains_key(&key("some.metric")),
                 "Should contain the metric key");
-        assert!(buckets.timers.contains_key("other.metric"),
+        assert!(buckets.timers.contains_key(&key("other.metric")),
                 "Should contain the metric key");
 
-        assert_eq!(Some(&vec![11.5, 99.5]), buckets.timers.get("some.metric"));
-        assert_eq!(Some(&vec![811.5]), buckets.timers.get("other.metric"));
+        assert_eq!(Some(&vec![11.5, 99.5]), buckets.timers.get(&key("some.metric")));
+        assert_eq!(Some(&vec![811.5]), buckets.timers.get(&key("other.metric")));
+        assert_eq!(Some(&2.0), buckets.timer_counts.get(&key("some.metric")));
+    }
+
+    #[test]
+    fn test_add_timer_metric_sampled() {
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("some.metric", 11.5, MetricKind::Timer(0.1)));
+        buckets.add(&Metric::new("some.metric", 12.5, MetricKind::Timer(0.5)));
+
+        assert_eq!(Some(&vec![11.5, 12.5]), buckets.timers.get(&key("some.metric")));
+        assert_eq!(Some(&12.0), buckets.timer_counts.get(&key("some.metric")));
+    }
+
+    #[test]
+    fn test_add_histogram_and_distribution_metric() {
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("some.metric", 11.5, MetricKind::Histogram(0.5)));
+        buckets.add(&Metric::new("some.metric", 12.5, MetricKind::Histogram(1.0)));
+        buckets.add(&Metric::new("some.metric", 1.5, MetricKind::Distribution(1.0)));
+
+        assert_eq!(0, buckets.timers().len());
+        assert_eq!(Some(&vec![11.5, 12.5]), buckets.histograms.get(&key("some.metric")));
+        assert_eq!(Some(&3.0), buckets.histogram_counts.get(&key("some.metric")));
+        assert_eq!(Some(&vec![1.5]), buckets.distributions.get(&key("some.metric")));
+        assert_eq!(Some(&1.0), buckets.distribution_counts.get(&key("some.metric")));
+
+        buckets.reset();
+        assert_eq!(Some(&vec![]), buckets.histograms.get(&key("some.metric")));
+        assert_eq!(Some(&0.0), buckets.distribution_counts.get(&key("some.metric")));
+    }
+
+    #[test]
+    fn test_add_timestamped_metric() {
+        let mut buckets = Buckets::new();
+        let now = time::get_time().sec;
+        let ts = now - 600;
+        buckets.add(&Metric::new("some.counter", 1.0, MetricKind::Counter(0.5)).with_timestamp(ts));
+        buckets.add(&Metric::new("some.counter", 1.0, MetricKind::Counter(1.0)).with_timestamp(ts));
+        buckets.add(&Metric::new("some.counter", 1.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("some.gauge", 4.0, MetricKind::Gauge).with_timestamp(ts));
+        buckets.add(&Metric::new("some.gauge", -1.0, MetricKind::GaugeDelta).with_timestamp(ts));
+
+        assert_eq!(Some(&1.0), buckets.counters.get(&key("some.counter")));
+        assert_eq!(Some(&3.0),
+                   buckets.timestamped_counters().get(&(key("some.counter"), ts)));
+        assert_eq!(Some(&3.0), buckets.timestamped_gauges().get(&(key("some.gauge"), ts)));
+        assert_eq!(0, buckets.gauges().len());
+        assert_eq!(5, buckets.total_messages());
+
+        buckets.reset();
+        assert_eq!(0, buckets.timestamped_counters().len());
+        assert_eq!(0, buckets.timestamped_gauges().len());
+    }
+
+    #[test]
+    fn test_add_timestamped_gauge_delta() {
+        let mut buckets = Buckets::new();
+        let ts = time::get_time().sec - 600;
+        buckets.add(&Metric::new("some.gauge", 100.0, MetricKind::Gauge));
+        buckets.add(&Metric::new("some.gauge", 1.0, MetricKind::GaugeDelta).with_timestamp(ts));
+        buckets.add(&Metric::new("other.gauge", -2.0, MetricKind::GaugeDelta).with_timestamp(ts));
+
+        assert_eq!(Some(&101.0), buckets.timestamped_gauges().get(&(key("some.gauge"), ts)));
+        assert_eq!(Some(&-2.0), buckets.timestamped_gauges().get(&(key("other.gauge"), ts)));
+        assert_eq!(Some(&100.0), buckets.gauges().get(&key("some.gauge")));
+    }
+
+    #[test]
+    fn test_add_timestamped_metric_outside_window() {
+        let mut buckets = Buckets::new();
+        buckets.set_timestamp_window(60);
+        let now = time::get_time().sec;
+        buckets.add(&Metric::new("old", 1.0, MetricKind::Counter(1.0)).with_timestamp(now - 61));
+        buckets.add(&Metric::new("new", 1.0, MetricKind::Counter(1.0)).with_timestamp(now + 61));
+        buckets.add(&Metric::new("ok", 1.0, MetricKind::Counter(1.0)).with_timestamp(now - 59));
+
+        assert_eq!(1, buckets.timestamped_counters().len());
+        assert_eq!(2, buckets.bad_messages());
+    }
+
+    #[test]
+    fn test_add_set_metric() {
+        let mut buckets = Buckets::new();
+        let metric = Metric::new("some.set", 0.0, MetricKind::Set("bob".to_string()));
+        buckets.add(&metric);
+        buckets.add(&metric);
+        buckets.add(&Metric::new("some.set", 0.0, MetricKind::Set("jane".to_string())));
+        buckets.add(&Metric::new("other.set", 0.0, MetricKind::Set("bob".to_string())));
+
+        assert_eq!(2, buckets.sets().len());
+        assert_eq!(2, buckets.sets.get(&key("some.set")).unwrap().len());
+        assert_eq!(1, buckets.sets.get(&key("other.set")).unwrap().len());
+        assert_eq!(0, buckets.counters().len());
+        assert_eq!(4, buckets.total_messages);
+    }
+
+    #[test]
+    fn test_add_tagged_metric() {
+        let mut buckets = Buckets::new();
+        let tags = vec![("env".to_string(), "prod".to_string())];
+        buckets.add(&Metric::new("some.metric", 1.0, MetricKind::Counter(1.0)));
+        buckets.add(&Metric::new("some.metric", 2.0, MetricKind::Counter(1.0))
+                        .with_tags(tags.clone()));
+        buckets.add(&Metric::new("some.metric", 3.0, MetricKind::Counter(1.0))
+                        .with_tags(tags.clone()));
+
+        assert_eq!(2, buckets.counters().len());
+        assert_eq!(Some(&1.0), buckets.counters.get(&key("some.metric")));
+        assert_eq!(Some(&5.0),
+                   buckets.counters.get(&MetricKey::new("some.metric", &tags)));
     }
 
     #[test]
     fn test_reset_metrics() {
         let mut buckets = Buckets::new();
-        buckets.add(&Metric::new("some.timer", 11.5, MetricKind::Timer));
+        buckets.add(&Metric::new("some.timer", 11.5, MetricKind::Timer(1.0)));
         buckets.add(&Metric::new("some.counter", 14.9, MetricKind::Counter(1.0)));
         buckets.add(&Metric::new("some.gauge", 0.9, MetricKind::Gauge));
+        buckets.add(&Metric::new("some.set", 0.0, MetricKind::Set("a".to_string())));
 
         buckets.reset();
-        assert!(buckets.timers.contains_key("some.timer"));
-        assert_eq!(Some(&vec![]), buckets.timers.get("some.timer"));
+        assert!(buckets.timers.contains_key(&key("some.timer")));
+        assert_eq!(Some(&vec![]), buckets.timers.get(&key("some.timer")));
+        assert_eq!(Some(&0.0), buckets.timer_counts.get(&key("some.timer")));
 
-        assert!(buckets.counters.contains_key("some.counter"));
-        assert_eq!(Some(&0.0), buckets.counters.get("some.counter"));
+        assert!(buckets.counters.contains_key(&key("some.counter")));
+        assert_eq!(Some(&0.0), buckets.counters.get(&key("some.counter")));
 
-        assert!(buckets.gauges.contains_key("some.gauge"));
-        assert_eq!(Some(&0.9), buckets.gauges.get("some.gauge"));
+        assert!(buckets.gauges.contains_key(&key("some.gauge")));
+        assert_eq!(Some(&0.9), buckets.gauges.get(&key("some.gauge")));
+
+        assert!(buckets.sets.contains_key(&key("some.set")));
+        assert_eq!(0, buckets.sets.get(&key("some.set")).unwrap().len());
 
         assert_eq!(0, buckets.total_messages);
         assert_eq!(0, buckets.bad_messages);
     }
+
+    #[test]
+    fn test_add_reload() {
+        let mut buckets = Buckets::new();
+        buckets.add_reload(Some("bad config".to_string()));
+        assert_eq!(Some("bad config"), buckets.last_reload_error());
+
+        buckets.add_reload(None);
+        buckets.reset();
+        assert_eq!(2, buckets.reloads());
+        assert_eq!(1, buckets.failed_reloads());
+        assert_eq!(None, buckets.last_reload_error());
+    }
+
+    #[test]
+    fn test_flush_time() {
+        let mut buckets = Buckets::new();
+        buckets.set_flush_time(1700000010, Duration::from_millis(5));
+        assert_eq!(1700000010, buckets.flush_timestamp());
+
+        // The lateness is kept for the admin stats.
+        buckets.reset();
+        assert!(buckets.flush_timestamp() > 1700000010);
+        assert_eq!(Duration::from_millis(5), buckets.flush_lateness());
+    }
+
+    #[test]
+    fn test_listener_health() {
+        let mut buckets = Buckets::new();
+        buckets.add_listener("udp 127.0.0.1:8125".to_string());
+        buckets.add_listener_error("udp 127.0.0.1:8125", "boom".to_string());
+        assert_eq!(ListenerState::Restarting, buckets.listeners()["udp 127.0.0.1:8125"].state);
+
+        buckets.add_listener_restart("udp 127.0.0.1:8125");
+        buckets.reset();
+        let stats = &buckets.listeners()["udp 127.0.0.1:8125"];
+        assert_eq!(ListenerState::Up, stats.state);
+        assert_eq!(1, stats.restarts);
+        assert_eq!(1, stats.errors);
+        assert_eq!(Some("boom".to_string()), stats.last_error);
+
+        buckets.add_listener_failure("udp 127.0.0.1:8125", "gone".to_string());
+        assert_eq!(ListenerState::Failed, buckets.listeners()["udp 127.0.0.1:8125"].state);
+    }
 }
@@ -3,15 +3,24 @@
 //! Used to parse the argv/config file into a struct that
 //! the server can consume and use as configuration data.
 
+use std::fs;
+
 use docopt::Docopt;
+use toml;
 
-static USAGE: &'static str = "
+static USAGE: &str = "
 Usage: statsd [options]
        statsd --help
 
 Options:
   -h, --help            Print help information.
   -p, --port=<p>        The UDP port to bind to [default: 8125].
+  --host=<hosts>        Comma separated hosts to receive metrics on, use :: for IPv4 and IPv6. [default: 0.0.0.0]
+  --tcp-port=<p>        Also accept newline delimited metrics over TCP on this port.
+  --socket-path=<path>  Also accept metric packets on a Unix datagram socket.
+  --stream-socket-path=<path>  Also accept newline delimited metrics on a Unix stream socket.
+  --socket-mode=<mode>  The octal file mode of the Unix sockets. [default: 660]
+  --max-connections=<n>  The number of TCP and Unix stream connections that can be open at once. [default: 1024]
   --flush-interval=<p>  How frequently to flush metrics to the backends in seconds. [default: 10].
   --console             Enable the console backend.
   --graphite            Enable the graphite backend.
@@ -19,20 +28,50 @@ Options:
   --graphite-host=<p>   The host graphite/carbon is running on. [default: 127.0.0.1]
   --admin-host=<p>      The host to bind the management server on. [default: 127.0.0.1]
   --admin-port=<p>      The port to bind the management server to. [default: 8126]
+  --admin-max-sessions=<n>  The number of management connections that can be open at once. [default: 16]
+  --admin-idle-timeout=<s>  Close management connections idle for this many seconds. [default: 300]
+  --timestamp-window=<s>  Accept client supplied timestamps this many seconds from now. [default: 3600]
+  --no-sanitize         Disable metric name sanitization.
+  --max-key-length=<n>  Reject metric names longer than this, 0 for no limit. [default: 0]
+  --udp-workers=<n>     The number of threads reading UDP packets on each host. [default: 1]
+  --udp-buffer-size=<n>  The largest UDP packet in bytes that can be received, up to 65536. [default: 65536]
+  --queue-capacity=<n>  The number of reads that can wait to be processed. [default: 10000]
+  --queue-policy=<p>    What to do with UDP packets when the queue is full, drop or block. [default: drop]
+  --shutdown-timeout=<s>  Seconds to wait for the final flush when stopping. [default: 10]
+  --config=<path>       Read backend and flush settings from a TOML file, reloaded on SIGHUP.
 ";
 
 /// Holds the parsed command line arguments
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Args {
     pub flag_port: u16,
+    pub flag_host: String,
+    pub flag_tcp_port: Option<u16>,
+    pub flag_socket_path: Option<String>,
+    pub flag_stream_socket_path: Option<String>,
+    pub flag_socket_mode: String,
+    pub flag_max_connections: usize,
     pub flag_admin_port: u16,
     pub flag_admin_host: String,
+    pub flag_admin_max_sessions: usize,
+    pub flag_admin_idle_timeout: u64,
     pub flag_flush_interval: u64,
     pub flag_console: bool,
     pub flag_graphite: bool,
     pub flag_graphite_port: u16,
     pub flag_graphite_host: String,
+    // Docopt prints the usage and exits on --help.
+    #[allow(dead_code)]
     pub flag_help: bool,
+    pub flag_timestamp_window: u64,
+    pub flag_no_sanitize: bool,
+    pub flag_max_key_length: usize,
+    pub flag_udp_buffer_size: usize,
+    pub flag_udp_workers: usize,
+    pub flag_queue_capacity: usize,
+    pub flag_queue_policy: String,
+    pub flag_shutdown_timeout: u64,
+    pub flag_config: Option<String>,
 }
 
 pub fn parse_args() -> Args {
@@ -41,3 +80,105 @@ pub fn parse_args() -> Args {
         .unwrap_or_else(|e| e.exit());
     args
 }
+
+/// The settings that can be read from the config file.
+///
+/// Keys are the same as the command line options
+/// and override them when present.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    flush_interval: Option<u64>,
+    console: Option<bool>,
+    graphite: Option<bool>,
+    graphite_port: Option<u16>,
+    graphite_host: Option<String>,
+    timestamp_window: Option<u64>,
+    no_sanitize: Option<bool>,
+    max_key_length: Option<usize>,
+}
+
+impl Args {
+    /// Apply the config file, if there is one, and check the settings.
+    ///
+    /// Called on startup and again for each reload.
+    pub fn with_config(&self) -> Result<Args, String> {
+        let mut args = self.clone();
+        if let Some(ref path) = self.flag_config {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read {}: {}", path, e))?;
+            args.apply(&contents).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+        }
+        if args.flag_flush_interval == 0 {
+            return Err("The flush interval must be at least 1 second".to_string());
+        }
+        Ok(args)
+    }
+
+    fn apply(&mut self, contents: &str) -> Result<(), toml::de::Error> {
+        let config: Config = toml::from_str(contents)?;
+        if let Some(interval) = config.flush_interval {
+            self.flag_flush_interval = interval;
+        }
+        if let Some(console) = config.console {
+            self.flag_console = console;
+        }
+        if let Some(graphite) = config.graphite {
+            self.flag_graphite = graphite;
+        }
+        if let Some(port) = config.graphite_port {
+            self.flag_graphite_port = port;
+        }
+        if let Some(host) = config.graphite_host {
+            self.flag_graphite_host = host;
+        }
+        if let Some(window) = config.timestamp_window {
+            self.flag_timestamp_window = window;
+        }
+        if let Some(no_sanitize) = config.no_sanitize {
+            self.flag_no_sanitize = no_sanitize;
+        }
+        if let Some(length) = config.max_key_length {
+            self.flag_max_key_length = length;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(argv: &[&str]) -> Args {
+        Docopt::new(USAGE)
+            .and_then(|d| d.argv(argv.iter()).deserialize())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let mut args = args(&["statsd", "--console", "--flush-interval=5"]);
+        args.apply("flush-interval = 30\ngraphite = true\ngraphite-host = \"10.0.0.5\"\n")
+            .unwrap();
+        assert_eq!(30, args.flag_flush_interval);
+        assert!(args.flag_console);
+        assert!(args.flag_graphite);
+        assert_eq!("10.0.0.5", args.flag_graphite_host);
+        assert_eq!(2003, args.flag_graphite_port);
+    }
+
+    #[test]
+    fn test_apply_config_unknown_key() {
+        let mut args = args(&["statsd"]);
+        assert!(args.apply("port = 9000\n").is_err());
+        assert!(args.apply("flush-interval = \"soon\"\n").is_err());
+    }
+
+    #[test]
+    fn test_with_config_validates() {
+        assert!(args(&["statsd"]).with_config().is_ok());
+        assert!(args(&["statsd", "--flush-interval=0"]).with_config().is_err());
+        assert!(args(&["statsd", "--config=/nonexistent/statsd.toml"]).with_config().is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! A UDP statsd client
+//!
+//! Metrics are written in the line protocol and batched into packets
+//! no larger than the configured packet size before being sent.
+
+use super::metric::{Metric, MetricKind};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use time;
+
+
+/// Packet size that fits in an ethernet MTU of 1500 bytes after
+/// the IPv6 and UDP headers.
+pub const DEFAULT_PACKET_SIZE: usize = 1432;
+
+
+/// Sends metrics to a statsd server.
+///
+/// Metrics are buffered until adding another would exceed the packet
+/// size, or `flush()` is called. The buffer is flushed when the client
+/// is dropped.
+pub struct StatsdClient {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    packet_size: usize,
+    buffer: String,
+    rng: u64,
+}
+
+impl StatsdClient {
+    /// Create a client that sends to the given server address.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> io::Result<StatsdClient> {
+        let addr = match addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No address given")),
+        };
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+
+        // Seed the sampling generator, it only needs to differ between clients.
+        let seed = time::precise_time_ns() ^ ((socket.local_addr()?.port() as u64) << 48);
+        Ok(StatsdClient {
+            socket,
+            addr,
+            packet_size: DEFAULT_PACKET_SIZE,
+            buffer: String::new(),
+            rng: seed | 1,
+        })
+    }
+
+    /// Set the maximum packet size in bytes.
+    ///
+    /// A single metric larger than this is sent in a packet on its own.
+    pub fn with_packet_size(mut self, packet_size: usize) -> StatsdClient {
+        self.packet_size = packet_size;
+        self
+    }
+
+    /// Increment a counter.
+    pub fn count(&mut self, name: &str, value: f64) -> io::Result<()> {
+        self.send(&Metric::new(name, value, MetricKind::Counter(1.0)))
+    }
+
+    /// Set a gauge to an absolute value.
+    pub fn gauge(&mut self, name: &str, value: f64) -> io::Result<()> {
+        self.send(&Metric::new(name, value, MetricKind::Gauge))
+    }
+
+    /// Record a timing in milliseconds.
+    pub fn timer(&mut self, name: &str, value: f64) -> io::Result<()> {
+        self.send(&Metric::new(name, value, MetricKind::Timer(1.0)))
+    }
+
+    /// Add a member to a set.
+    pub fn set(&mut self, name: &str, member: &str) -> io::Result<()> {
+        self.send(&Metric::new(name, 0.0, MetricKind::Set(member.to_string())))
+    }
+
+    /// Add a metric to the current packet.
+    pub fn send(&mut self, metric: &Metric) -> io::Result<()> {
+        let line = metric.to_string();
+        if !self.buffer.is_empty() && self.buffer.len() + 1 + line.len() > self.packet_size {
+            self.flush()?;
+        }
+        if !self.buffer.is_empty() {
+            self.buffer.push('\n');
+        }
+        self.buffer.push_str(&line);
+        if self.buffer.len() >= self.packet_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Send a metric for only a `rate` fraction of calls.
+    ///
+    /// Counters, timers, histograms and distributions are sent with the
+    /// sample rate so the server can scale them back up. Other kinds
+    /// can't be scaled and are always sent.
+    pub fn send_sampled(&mut self, metric: &Metric, rate: f64) -> io::Result<()> {
+        let kind = match metric.kind {
+            MetricKind::Counter(_) => MetricKind::Counter(rate),
+            MetricKind::Timer(_) => MetricKind::Timer(rate),
+            MetricKind::Histogram(_) => MetricKind::Histogram(rate),
+            MetricKind::Distribution(_) => MetricKind::Distribution(rate),
+            _ => return self.send(metric),
+        };
+        if rate < 1.0 && self.random() >= rate {
+            return Ok(());
+        }
+        let mut sampled = metric.clone();
+        sampled.kind = kind;
+        self.send(&sampled)
+    }
+
+    /// Send any buffered metrics.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let result = self.socket.send_to(self.buffer.as_bytes(), self.addr);
+        self.buffer.clear();
+        result.map(|_| ())
+    }
+
+    /// Get a random number in [0, 1) using xorshift64*.
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let value = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Drop for StatsdClient {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::metric::{Metric, MetricKind};
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    fn server() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        socket
+    }
+
+    fn recv(socket: &UdpSocket) -> String {
+        let mut buf = [0; 2048];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_client_batches_until_flush() {
+        let server = server();
+        let mut client = StatsdClient::new(server.local_addr().unwrap()).unwrap();
+        client.count("foo", 1.0).unwrap();
+        client.gauge("bar", 2.5).unwrap();
+        client.timer("baz", 12.0).unwrap();
+        client.set("users", "bob").unwrap();
+        client.flush().unwrap();
+
+        assert_eq!("foo:1|c\nbar:2.5|g\nbaz:12|ms\nusers:bob|s", recv(&server));
+    }
+
+    #[test]
+    fn test_client_packet_size() {
+        let server = server();
+        let mut client = StatsdClient::new(server.local_addr().unwrap())
+            .unwrap()
+            .with_packet_size(16);
+        client.count("foo", 1.0).unwrap();
+        client.count("bar", 1.0).unwrap();
+        client.count("a.very.long.name", 1.0).unwrap();
+        drop(client);
+
+        assert_eq!("foo:1|c\nbar:1|c", recv(&server));
+        assert_eq!("a.very.long.name:1|c", recv(&server));
+    }
+
+    #[test]
+    fn test_client_sampled() {
+        let server = server();
+        let mut client = StatsdClient::new(server.local_addr().unwrap()).unwrap();
+        let metric = Metric::new("foo", 1.0, MetricKind::Counter(1.0));
+        for _ in 0..1000 {
+            client.send_sampled(&metric, 0.5).unwrap();
+        }
+        client.flush().unwrap();
+
+        let mut sent = 0;
+        server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut buf = [0; 2048];
+        while let Ok(len) = server.recv(&mut buf) {
+            let packet = String::from_utf8(buf[..len].to_vec()).unwrap();
+            for metric in Metric::parse(&packet).unwrap() {
+                assert_eq!(MetricKind::Counter(0.5), metric.kind);
+                sent += 1;
+            }
+        }
+        assert!(sent > 350 && sent < 650, "sent {} of 1000", sent);
+    }
+
+    #[test]
+    fn test_client_sampled_gauge_always_sent() {
+        let server = server();
+        let mut client = StatsdClient::new(server.local_addr().unwrap()).unwrap();
+        for _ in 0..10 {
+            client.send_sampled(&Metric::new("foo", 1.0, MetricKind::Gauge), 0.01).unwrap();
+        }
+        client.flush().unwrap();
+
+        assert_eq!(10, recv(&server).lines().count());
+    }
+}
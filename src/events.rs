@@ -0,0 +1,447 @@
+//! DogStatsD events and service checks
+//!
+//! Events are sent as
+//! `_e{<title_len>,<text_len>}:<title>|<text>|d:<timestamp>|h:<host>|#<tags>`
+//! and service checks as `_sc|<name>|<status>|d:<timestamp>|h:<host>|#<tags>|m:<message>`.
+//! Both can be mixed with metrics in a packet, one per line.
+
+use std::fmt;
+use std::str;
+use super::metric::{Metric, ParseError, Tags};
+
+
+/// An event, e.g. a deploy annotation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub title: String,
+    pub text: String,
+    pub timestamp: Option<i64>,
+    pub hostname: Option<String>,
+    pub aggregation_key: Option<String>,
+    pub priority: Option<String>,
+    pub source_type: Option<String>,
+    pub alert_type: Option<String>,
+    pub tags: Tags,
+}
+
+
+/// Service check statuses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckStatus {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            CheckStatus::Ok => "OK",
+            CheckStatus::Warning => "WARNING",
+            CheckStatus::Critical => "CRITICAL",
+            CheckStatus::Unknown => "UNKNOWN",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+
+/// The result of a health check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    pub timestamp: Option<i64>,
+    pub hostname: Option<String>,
+    pub message: Option<String>,
+    pub tags: Tags,
+}
+
+
+/// Anything that can be sent as a line in a packet.
+#[derive(Debug)]
+pub enum Message {
+    Metric(Metric),
+    Event(Event),
+    ServiceCheck(ServiceCheck),
+}
+
+
+/// Parse each line in a packet.
+///
+/// Lines are parsed independently, a result is returned for each
+/// metric, event or service check. Blank lines are skipped.
+pub fn parse_packet(source: &str) -> Vec<Result<Message, ParseError>> {
+    let mut results = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i + 1;
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with("_e{") {
+            results.push(Event::parse(line, line_no).map(Message::Event));
+        } else if line.starts_with("_sc|") {
+            results.push(ServiceCheck::parse(line, line_no).map(Message::ServiceCheck));
+        } else {
+            results.extend(Metric::parse_line(line, line_no)
+                               .into_iter()
+                               .map(|result| result.map(Message::Metric)));
+        }
+    }
+    results
+}
+
+
+/// The messages in a raw packet, parsed before it is added to the buckets.
+///
+/// Parsing doesn't need the buckets so it can be done on reader threads.
+#[derive(Debug)]
+pub struct ParsedPacket {
+    pub messages: Vec<Result<Message, ParseError>>,
+    /// The packet is not valid UTF-8 or contains no messages.
+    pub invalid: bool,
+    /// The packet was cut short by the receive buffer.
+    pub truncated: bool,
+}
+
+impl ParsedPacket {
+    /// Parse a raw packet.
+    ///
+    /// When the packet is `truncated` the partial last line is dropped.
+    pub fn parse(packet: &[u8], truncated: bool) -> ParsedPacket {
+        let packet = if truncated {
+            match packet.iter().rposition(|&b| b == b'\n') {
+                Some(end) => &packet[..end],
+                None => &packet[..0],
+            }
+        } else {
+            packet
+        };
+        let messages = match str::from_utf8(packet) {
+            Ok(source) => parse_packet(source),
+            Err(_) => {
+                return ParsedPacket {
+                    messages: Vec::new(),
+                    invalid: true,
+                    truncated,
+                }
+            }
+        };
+        // Only the dropped line is bad when nothing is left of a truncated packet.
+        let invalid = messages.is_empty() && !truncated;
+        ParsedPacket {
+            messages,
+            invalid,
+            truncated,
+        }
+    }
+}
+
+
+impl Event {
+    /// Create an event with only a title and text.
+    pub fn new<S: Into<String>>(title: S, text: S) -> Event {
+        Event {
+            title: title.into(),
+            text: text.into(),
+            timestamp: None,
+            hostname: None,
+            aggregation_key: None,
+            priority: None,
+            source_type: None,
+            alert_type: None,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Parses an event line.
+    ///
+    /// The title and text lengths are in bytes. Newlines in the
+    /// text are sent escaped as `\n`.
+    pub fn parse(line: &str, line_no: usize) -> Result<Event, ParseError> {
+        if !line.starts_with("_e{") {
+            return Err(ParseError::UnknownType(line_no, 0));
+        }
+        // Get the title and text lengths from `_e{<title>,<text>}:`
+        let header_end = match line.find("}:") {
+            Some(pos) => pos,
+            None => return Err(ParseError::BadValue(line_no, 3)),
+        };
+        let lengths: Vec<Option<usize>> = line[3..header_end]
+            .split(',')
+            .map(|len| len.parse::<usize>().ok())
+            .collect();
+        let (title_len, text_len) = match lengths[..] {
+            [Some(title), Some(text)] => (title, text),
+            _ => return Err(ParseError::BadValue(line_no, 3)),
+        };
+
+        let title_start = header_end + 2;
+        let title_end = title_start.saturating_add(title_len);
+        let title = match line.get(title_start..title_end) {
+            Some(title) if !title.is_empty() => title,
+            _ => return Err(ParseError::BadName(line_no, title_start)),
+        };
+        if line.get(title_end..title_end + 1) != Some("|") {
+            return Err(ParseError::BadName(line_no, title_end));
+        }
+
+        let text_start = title_end + 1;
+        let text_end = text_start.saturating_add(text_len);
+        let text = match line.get(text_start..text_end) {
+            Some(text) => text,
+            None => return Err(ParseError::BadValue(line_no, text_start)),
+        };
+
+        let mut event = Event::new(title.to_string(), text.replace("\\n", "\n"));
+        let mut idx = text_end;
+        for field in split_fields(&line[text_end..], line_no, text_end)? {
+            idx += 1;
+            if let Some(raw) = field.strip_prefix("d:") {
+                event.timestamp = Some(parse_timestamp(raw, line_no, idx)?);
+            } else if let Some(raw) = field.strip_prefix("h:") {
+                event.hostname = Some(raw.to_string());
+            } else if let Some(raw) = field.strip_prefix("k:") {
+                event.aggregation_key = Some(raw.to_string());
+            } else if let Some(raw) = field.strip_prefix("p:") {
+                event.priority = Some(raw.to_string());
+            } else if let Some(raw) = field.strip_prefix("s:") {
+                event.source_type = Some(raw.to_string());
+            } else if let Some(raw) = field.strip_prefix("t:") {
+                event.alert_type = Some(raw.to_string());
+            } else if let Some(raw) = field.strip_prefix('#') {
+                event.tags = Metric::parse_tags(raw);
+            } else if !field.is_empty() {
+                return Err(ParseError::TrailingData(line_no, idx));
+            }
+            idx += field.len();
+        }
+        Ok(event)
+    }
+}
+
+
+impl ServiceCheck {
+    /// Create a service check with only a name and status.
+    pub fn new<S: Into<String>>(name: S, status: CheckStatus) -> ServiceCheck {
+        ServiceCheck {
+            name: name.into(),
+            status,
+            timestamp: None,
+            hostname: None,
+            message: None,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Parses a service check line.
+    ///
+    /// The `m:` message field has to be last as it can contain `|`.
+    pub fn parse(line: &str, line_no: usize) -> Result<ServiceCheck, ParseError> {
+        if !line.starts_with("_sc|") {
+            return Err(ParseError::UnknownType(line_no, 0));
+        }
+        let mut fields = line[4..].splitn(3, '|');
+        let name = fields.next().unwrap_or("");
+        if name.is_empty() {
+            return Err(ParseError::BadName(line_no, 4));
+        }
+
+        let status_start = 4 + name.len() + 1;
+        let status = match fields.next() {
+            Some("0") => CheckStatus::Ok,
+            Some("1") => CheckStatus::Warning,
+            Some("2") => CheckStatus::Critical,
+            Some("3") => CheckStatus::Unknown,
+            _ => return Err(ParseError::BadValue(line_no, status_start.min(line.len()))),
+        };
+
+        let mut check = ServiceCheck::new(name, status);
+        let mut idx = status_start + 1;
+        let mut rest = fields.next().unwrap_or("");
+        while !rest.is_empty() {
+            if let Some(message) = rest.strip_prefix("m:") {
+                check.message = Some(message.to_string());
+                break;
+            }
+            let (field, remaining) = match rest.find('|') {
+                Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+                None => (rest, ""),
+            };
+            idx += 1;
+            if let Some(raw) = field.strip_prefix("d:") {
+                check.timestamp = Some(parse_timestamp(raw, line_no, idx)?);
+            } else if let Some(raw) = field.strip_prefix("h:") {
+                check.hostname = Some(raw.to_string());
+            } else if let Some(raw) = field.strip_prefix('#') {
+                check.tags = Metric::parse_tags(raw);
+            } else if !field.is_empty() {
+                return Err(ParseError::TrailingData(line_no, idx));
+            }
+            idx += field.len();
+            rest = remaining;
+        }
+        Ok(check)
+    }
+}
+
+
+/// Split the `|field|field` section following an event's text.
+fn split_fields(rest: &str, line_no: usize, offset: usize) -> Result<Vec<&str>, ParseError> {
+    if rest.is_empty() {
+        return Ok(Vec::new());
+    }
+    match rest.strip_prefix('|') {
+        Some(fields) => Ok(fields.split('|').collect()),
+        None => Err(ParseError::TrailingData(line_no, offset)),
+    }
+}
+
+
+/// Parse a unix timestamp field.
+fn parse_timestamp(raw: &str, line_no: usize, column: usize) -> Result<i64, ParseError> {
+    raw.parse::<i64>().map_err(|_| ParseError::BadValue(line_no, column))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::metric::ParseError;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_parsed_packet() {
+        let packet = ParsedPacket::parse(b"foo:1|c\nbad\n_sc|db|0", false);
+        assert_eq!(3, packet.messages.len());
+        assert!(packet.messages[1].is_err());
+        assert!(!packet.invalid);
+        assert!(!packet.truncated);
+
+        assert!(ParsedPacket::parse(b"\xff:1|c", false).invalid);
+        assert!(ParsedPacket::parse(b"\n", false).invalid);
+    }
+
+    #[test]
+    fn test_parsed_packet_truncated() {
+        let packet = ParsedPacket::parse(b"foo:1|c\nbar:2|c\nbaz:3", true);
+        assert_eq!(2, packet.messages.len());
+        assert!(packet.truncated);
+        assert!(!packet.invalid);
+
+        let packet = ParsedPacket::parse(b"foo:1", true);
+        assert!(packet.messages.is_empty());
+        assert!(!packet.invalid);
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let event = Event::parse("_e{6,11}:Deploy|Version 1.2", 1).unwrap();
+        assert_eq!("Deploy", event.title);
+        assert_eq!("Version 1.2", event.text);
+        assert_eq!(None, event.timestamp);
+    }
+
+    #[test]
+    fn test_parse_event_fields() {
+        let line = "_e{6,12}:Deploy|line1\\nline2|d:1700000000|h:web1|k:abc|p:low|s:ci|t:success\
+                    |#env:prod,canary";
+        let event = Event::parse(line, 1).unwrap();
+        assert_eq!("line1\nline2", event.text);
+        assert_eq!(Some(1700000000), event.timestamp);
+        assert_eq!(Some("web1".to_string()), event.hostname);
+        assert_eq!(Some("abc".to_string()), event.aggregation_key);
+        assert_eq!(Some("low".to_string()), event.priority);
+        assert_eq!(Some("ci".to_string()), event.source_type);
+        assert_eq!(Some("success".to_string()), event.alert_type);
+        assert_eq!(2, event.tags.len());
+    }
+
+    #[test]
+    fn test_parse_event_text_with_pipes() {
+        let event = Event::parse("_e{1,3}:a|b|c|t:info", 1).unwrap();
+        assert_eq!("b|c", event.text);
+        assert_eq!(Some("info".to_string()), event.alert_type);
+    }
+
+    #[test]
+    fn test_parse_event_invalid() {
+        let cases = vec![("_e{", ParseError::BadValue(1, 3)),
+                         ("_e{a,1}:a|b", ParseError::BadValue(1, 3)),
+                         ("_e{1}:a|b", ParseError::BadValue(1, 3)),
+                         ("_e{0,1}:|b", ParseError::BadName(1, 8)),
+                         ("_e{9,1}:a|b", ParseError::BadName(1, 8)),
+                         ("_e{2,1}:ab:c", ParseError::BadName(1, 10)),
+                         ("_e{1,5}:a|b", ParseError::BadValue(1, 10)),
+                         ("_e{1,1}:a|bc", ParseError::TrailingData(1, 11)),
+                         ("_e{1,1}:a|b|d:x", ParseError::BadValue(1, 12)),
+                         ("_e{1,1}:a|b|x:1", ParseError::TrailingData(1, 12)),
+                         ("_e{1,1}:é|b", ParseError::BadName(1, 8)),
+                         ("foo:1|c", ParseError::UnknownType(1, 0))];
+        for (input, expected) in cases {
+            assert_eq!(Err(expected), Event::parse(input, 1), "input {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_service_check() {
+        let check = ServiceCheck::parse("_sc|db.up|2", 1).unwrap();
+        assert_eq!("db.up", check.name);
+        assert_eq!(CheckStatus::Critical, check.status);
+        assert_eq!("CRITICAL", format!("{}", check.status));
+
+        let line = "_sc|db.up|0|d:1700000000|h:db1|#env:prod|m:all good | really";
+        let check = ServiceCheck::parse(line, 1).unwrap();
+        assert_eq!(CheckStatus::Ok, check.status);
+        assert_eq!(Some(1700000000), check.timestamp);
+        assert_eq!(Some("db1".to_string()), check.hostname);
+        assert_eq!(1, check.tags.len());
+        assert_eq!(Some("all good | really".to_string()), check.message);
+    }
+
+    #[test]
+    fn test_parse_service_check_invalid() {
+        let cases = vec![("_sc|", ParseError::BadName(1, 4)),
+                         ("_sc||0", ParseError::BadName(1, 4)),
+                         ("_sc|a", ParseError::BadValue(1, 5)),
+                         ("_sc|a|4", ParseError::BadValue(1, 6)),
+                         ("_sc|a|0|d:x", ParseError::BadValue(1, 8)),
+                         ("_sc|a|0|z:1", ParseError::TrailingData(1, 8)),
+                         ("foo:1|c", ParseError::UnknownType(1, 0))];
+        for (input, expected) in cases {
+            assert_eq!(Err(expected), ServiceCheck::parse(input, 1), "input {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_packet_mixed() {
+        let results = parse_packet("a:1|c\n_e{1,1}:a|b\n\n_sc|x|1\nbad\nb:1|c:2|c");
+        assert_eq!(6, results.len());
+        match results[1] {
+            Ok(Message::Event(ref event)) => assert_eq!("a", event.title),
+            ref other => panic!("unexpected {:?}", other),
+        }
+        match results[2] {
+            Ok(Message::ServiceCheck(ref check)) => assert_eq!("x", check.name),
+            ref other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(ParseError::BadName(5, 3), *results[3].as_ref().unwrap_err());
+        let metrics = results.iter().filter(|r| matches!(**r, Ok(Message::Metric(_))));
+        assert_eq!(3, metrics.count());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_parse_packet_never_panics(packet in prop::collection::vec(any::<u8>(), 0..64),
+                                          truncated in any::<bool>()) {
+            let _ = ParsedPacket::parse(&packet, truncated);
+        }
+
+        #[test]
+        fn prop_parse_packet_never_panics_on_event_like(
+            packet in "(_e\\{[0-9,]{0,6}\\}:|_sc\\|)?[a-z|:#,0-9\\-\\n]{0,40}") {
+            let _ = ParsedPacket::parse(packet.as_bytes(), false);
+        }
+    }
+}
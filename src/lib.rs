@@ -0,0 +1,34 @@
+//! A statsd server
+//!
+//! The server can be embedded in other programs with `server::Server`,
+//! the `statsd` binary is a thin command line wrapper around it.
+
+extern crate time;
+extern crate socket2;
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+#[cfg(test)]
+extern crate proptest;
+
+
+pub mod metric;
+pub mod events;
+pub mod server;
+pub mod buckets;
+pub mod backend;
+pub mod metric_processor;
+pub mod sanitize;
+pub mod client;
+pub mod queue;
+mod management;
+mod net;
+mod udp;
+mod stream;
+mod supervisor;
+#[cfg(unix)]
+mod unix;
+pub mod backends {
+    pub mod console;
+    pub mod graphite;
+}
@@ -4,106 +4,247 @@ extern crate serde_derive;
 extern crate serde;
 extern crate time;
 extern crate docopt;
+extern crate toml;
+extern crate statsd;
+#[cfg(unix)]
+extern crate signal_hook;
 
-use std::thread;
-use std::sync::mpsc::channel;
-use std::str;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process;
+use std::time::Duration;
+
+use statsd::backend::{self, Backend};
+use statsd::queue::QueuePolicy;
+use statsd::sanitize::Sanitizer;
+use statsd::server::{Reload, Server, ServerHandle};
 
 
 // Local module imports.
-mod metric;
 mod cli;
-mod server;
-mod buckets;
-mod backend;
-mod management;
-mod metric_processor;
-mod backends {
-    pub mod console;
-    pub mod graphite;
-}
 
 
 fn main() {
-    let args = cli::parse_args();
+    let cli_args = cli::parse_args();
+    let args = match cli_args.with_config() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let admin_addr = match (args.flag_admin_host.as_str(), args.flag_admin_port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next()) {
+        Some(addr) => addr,
+        None => {
+            eprintln!("Invalid admin host {}", args.flag_admin_host);
+            process::exit(1);
+        }
+    };
+
+    let mut server = Server::new()
+                         .admin_addr(admin_addr)
+                         .max_connections(args.flag_max_connections)
+                         .admin_max_sessions(args.flag_admin_max_sessions)
+                         .admin_idle_timeout(args.flag_admin_idle_timeout)
+                         .flush_interval(args.flag_flush_interval)
+                         .udp_buffer_size(args.flag_udp_buffer_size)
+                         .udp_workers(args.flag_udp_workers)
+                         .queue_capacity(args.flag_queue_capacity)
+                         .queue_policy(queue_policy(&args.flag_queue_policy))
+                         .timestamp_window(args.flag_timestamp_window)
+                         .sanitizer(Sanitizer::new(!args.flag_no_sanitize,
+                                                   args.flag_max_key_length));
+    let backends = match backends(&args) {
+        Ok(backends) => backends,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    for addr in resolve_hosts(&args.flag_host, args.flag_port) {
+        server = server.udp_addr(addr);
+    }
+    if let Some(port) = args.flag_tcp_port {
+        for addr in resolve_hosts(&args.flag_host, port) {
+            server = server.tcp_addr(addr);
+        }
+    }
+    server = unix_sockets(server, &args);
+    for backend in backends.into_vec() {
+        server = server.backend(backend);
+    }
 
-    let mut backends = backend::factory(&args.flag_console,
-                                        &args.flag_graphite,
-                                        &args.flag_graphite_host,
-                                        &args.flag_graphite_port);
+    println!("Starting statsd - {}", time::now().rfc822());
+    let server = match server.start() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Unable to start server: {}", e);
+            process::exit(1);
+        }
+    };
+    for addr in server.udp_addrs() {
+        println!("Data server on {}", addr);
+    }
+    for addr in server.tcp_addrs() {
+        println!("TCP data server on {}", addr);
+    }
+    println!("Admin server on {}", server.admin_addr());
 
-    let (event_send, event_recv) = channel();
-    let flush_send = event_send.clone();
-    let udp_send = event_send.clone();
-    let tcp_send = event_send.clone();
+    handle_signals(&server, &cli_args);
+    if let Err(e) = server.join() {
+        eprintln!("Stopping statsd: {}", e);
+        process::exit(1);
+    }
+}
 
-    let mut buckets = buckets::Buckets::new();
 
-    println!("Starting statsd - {}",
-             time::at(buckets.start_time()).rfc822().to_string());
-    println!("Data server on 0.0.0.0:{}", args.flag_port);
-    println!("Admin server on {}:{}",
-             args.flag_admin_host,
-             args.flag_admin_port);
+/// Resolve a comma separated list of hosts to the addresses to listen on.
+///
+/// IPv6 addresses can be written with or without brackets.
+fn resolve_hosts(hosts: &str, port: u16) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for host in hosts.split(',').map(|h| h.trim()).filter(|h| !h.is_empty()) {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match (host, port).to_socket_addrs() {
+            Ok(resolved) => {
+                for addr in resolved {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Invalid host {}: {}", host, e);
+                process::exit(1);
+            }
+        }
+    }
+    if addrs.is_empty() {
+        eprintln!("No hosts to listen on");
+        process::exit(1);
+    }
+    addrs
+}
 
-    // Setup the UDP server which publishes events to the event channel
-    let port = args.flag_port;
-    thread::spawn(move || {
-        server::udp_server(udp_send, port);
-    });
+/// Parse the policy for a full event queue.
+fn queue_policy(policy: &str) -> QueuePolicy {
+    match policy {
+        "drop" => QueuePolicy::DropNewest,
+        "block" => QueuePolicy::Block,
+        _ => {
+            eprintln!("Invalid queue policy {}, use drop or block", policy);
+            process::exit(1);
+        }
+    }
+}
 
-    // Setup the TCP server for administration
-    let tcp_port = args.flag_admin_port;
-    let tcp_host = args.flag_admin_host;
-    thread::spawn(move || {
-        server::admin_server(tcp_send, tcp_port, &tcp_host);
-    });
+/// Add the Unix socket listeners from the command line arguments.
+#[cfg(unix)]
+fn unix_sockets(mut server: Server, args: &cli::Args) -> Server {
+    let mode = match u32::from_str_radix(&args.flag_socket_mode, 8) {
+        Ok(mode) => mode,
+        Err(_) => {
+            eprintln!("Invalid socket mode {}", args.flag_socket_mode);
+            process::exit(1);
+        }
+    };
+    server = server.unix_socket_mode(mode);
+    if let Some(ref path) = args.flag_socket_path {
+        server = server.unix_datagram_path(path.as_str());
+    }
+    if let Some(ref path) = args.flag_stream_socket_path {
+        server = server.unix_stream_path(path.as_str());
+    }
+    server
+}
 
-    // Run the timer that flushes metrics to the backends.
-    let flush_interval = args.flag_flush_interval;
-    thread::spawn(move || {
-        server::flush_timer_loop(flush_send, flush_interval);
-    });
+#[cfg(not(unix))]
+fn unix_sockets(server: Server, args: &cli::Args) -> Server {
+    if args.flag_socket_path.is_some() || args.flag_stream_socket_path.is_some() {
+        eprintln!("Unix sockets are not supported on this platform");
+        process::exit(1);
+    }
+    server
+}
 
-    // Main event loop.
-    loop {
-        let result = match event_recv.recv() {
-            Ok(res) => res,
-            Err(e) => panic!(format!("Event channel has hung up: {:?}", e)),
-        };
-
-        match result {
-            server::Event::TimerFlush => {
-                buckets.process();
-                for backend in backends.iter_mut() {
-                    backend.flush_buckets(&buckets);
-                }
-                buckets.reset();
-            }
+/// Create the backends enabled in the settings.
+fn backends(args: &cli::Args) -> Result<Box<[Box<dyn Backend>]>, String> {
+    backend::factory(&args.flag_console,
+                     &args.flag_graphite,
+                     &args.flag_graphite_host,
+                     &args.flag_graphite_port)
+        .map_err(|e| format!("Invalid graphite host {}: {}", args.flag_graphite_host, e))
+}
 
-            server::Event::UdpMessage(buf) => {
-                // Create the metric and push it into the buckets.
-                str::from_utf8(&buf)
-                    .map(|val| {
-                        metric::Metric::parse(&val)
-                            .and_then(|metrics| {
-                                for metric in metrics.iter() {
-                                    buckets.add(&metric);
-                                }
-                                Ok(metrics.len())
-                            })
-                            .or_else(|err| {
-                                buckets.add_bad_message();
-                                Err(err)
-                            })
-                            .ok();
-                    })
-                    .ok();
-            }
+/// Build the settings that are replaced on reload.
+fn reload_settings(args: &cli::Args) -> Result<Reload, String> {
+    let mut reload = Reload::new()
+                         .flush_interval(args.flag_flush_interval)
+                         .timestamp_window(args.flag_timestamp_window)
+                         .sanitizer(Sanitizer::new(!args.flag_no_sanitize,
+                                                   args.flag_max_key_length));
+    for backend in backends(args)?.into_vec() {
+        reload = reload.backend(backend);
+    }
+    Ok(reload)
+}
 
-            server::Event::TcpMessage(stream) => {
-                management::exec(stream, &mut buckets);
+/// Reload the config file on SIGHUP, and stop the server with a
+/// final flush on SIGTERM or SIGINT.
+///
+/// The process exits with an error if the flush doesn't finish within
+/// the shutdown timeout, or immediately on a second stop signal.
+#[cfg(unix)]
+fn handle_signals(server: &ServerHandle, args: &cli::Args) {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+    use std::thread::{self, sleep};
+
+    let mut signals = match Signals::new([SIGTERM, SIGINT, SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Unable to register signal handlers: {}", e);
+            process::exit(1);
+        }
+    };
+    let reloader = server.reload_handle();
+    let shutdown = server.shutdown_handle();
+    let args = args.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal != SIGHUP {
+                break;
+            }
+            // Start from the command line so removed keys go back to their defaults.
+            match args.with_config().and_then(|reloaded| reload_settings(&reloaded)) {
+                Ok(reload) => {
+                    reloader.reload(reload);
+                    println!("Reloaded configuration - {}", time::now().rfc822());
+                }
+                Err(e) => {
+                    eprintln!("Unable to reload configuration: {}", e);
+                    reloader.reload_failed(e);
+                }
             }
         }
-    }
+        println!("Shutting down - {}", time::now().rfc822());
+        shutdown.shutdown();
+
+        let timeout = Duration::from_secs(args.flag_shutdown_timeout);
+        thread::spawn(move || {
+            sleep(timeout);
+            eprintln!("Timed out waiting for the final flush");
+            process::exit(1);
+        });
+        if signals.forever().any(|signal| signal != SIGHUP) {
+            eprintln!("Stopping immediately");
+            process::exit(1);
+        }
+    });
 }
+
+#[cfg(not(unix))]
+fn handle_signals(_server: &ServerHandle, _args: &cli::Args) {}
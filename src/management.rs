@@ -1,82 +1,189 @@
 use buckets::Buckets;
+use net;
+use queue::EventSender;
+use server;
 use time;
 use std::net::TcpStream;
 use std::io::{BufReader, BufRead, Write};
 use std::fmt::Write as fmtWrite;
+use std::sync::Mutex;
+use std::time::Duration;
 
 
-/// Handle the management commands
-/// returning the response to send back.
-pub fn exec(stream: TcpStream, buckets: &mut Buckets) {
+/// Read commands from an admin connection until the client
+/// quits, disconnects or is idle for `idle_timeout`.
+///
+/// Runs on its own thread. Commands run on the buckets shared with
+/// the event loop, so they don't wait behind queued metrics. The
+/// queue counters are read from `chan`.
+pub fn session(stream: TcpStream,
+               buckets: &Mutex<Buckets>,
+               chan: &EventSender,
+               idle_timeout: Duration) {
+    if stream.set_read_timeout(Some(idle_timeout)).is_err() {
+        return;
+    }
     let mut reader = BufReader::new(stream);
     let mut done = false;
 
     while !done {
         let mut buffer = String::new();
-        let _ = reader.read_line(&mut buffer);
-        let command = buffer.split_whitespace()
-                            .next()
-                            .unwrap_or("")
-                            .to_lowercase();
-        let mut writer = reader.get_mut();
-        let mut out = String::new();
+        let command = match reader.read_line(&mut buffer) {
+            // Disconnected.
+            Ok(0) => return,
+            Ok(_) => {
+                buffer.split_whitespace()
+                      .next()
+                      .unwrap_or("")
+                      .to_lowercase()
+            }
+            Err(ref e) if net::timed_out(e) => {
+                let _ = reader.get_mut().write_all(b"Idle timeout, good bye!\n\n");
+                return;
+            }
+            Err(_) => return,
+        };
 
-        // Trigger Deref<Target = str>
-        match &*command {
-            "help" => {
-                out.push_str("Statsd Admin Console:\n");
-                out.push_str("\n");
-                out.push_str("Available commands:\n");
-                out.push_str("stats    - print server stats.\n");
-                out.push_str("counters - print counter data.\n");
-                out.push_str("gauges   - print gauge data.\n");
-                out.push_str("timers   - print timer data.\n");
-                out.push_str("clear    - clear stored metrics.\n");
-                out.push_str("quit     - close this connection.\n");
+        let out = match &*command {
+            "quit" => {
+                done = true;
+                "Good bye!\n\n".to_string()
             }
-            "stats" => {
-                let uptime = (time::get_time() - buckets.start_time()).num_seconds();
-                write!(out, "uptime: {} seconds\n", uptime).unwrap();
-                write!(out, "bad_messages: {}\n", buckets.bad_messages()).unwrap();
-                write!(out, "total_messages: {}\n", buckets.total_messages()).unwrap();
-                write!(out, "END\n\n").unwrap();
+            "" => continue,
+            _ => {
+                let mut buckets = server::lock_buckets(buckets);
+                buckets.set_queue_stats(chan.stats());
+                exec(&command, &mut buckets)
             }
-            "counters" => {
-                for (key, value) in buckets.counters().iter() {
-                    write!(out, " {}: {}\n", key, value).unwrap();
-                }
-                write!(out, "END\n\n").unwrap();
+        };
+        let writer = reader.get_mut();
+        if writer.write_all(out.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+
+/// Handle the management commands
+/// returning the response to send back.
+pub fn exec(command: &str, buckets: &mut Buckets) -> String {
+    let mut out = String::new();
+
+    match command {
+        "help" => {
+            out.push_str("Statsd Admin Console:\n");
+            out.push('\n');
+            out.push_str("Available commands:\n");
+            out.push_str("stats    - print server stats.\n");
+            out.push_str("counters - print counter data.\n");
+            out.push_str("gauges   - print gauge data.\n");
+            out.push_str("timers   - print timer data.\n");
+            out.push_str("sets     - print set cardinality.\n");
+            out.push_str("clear    - clear stored metrics.\n");
+            out.push_str("quit     - close this connection.\n");
+        }
+        "stats" => {
+            let uptime = (time::get_time() - buckets.start_time()).num_seconds();
+            writeln!(out, "uptime: {} seconds", uptime).unwrap();
+            writeln!(out, "bad_messages: {}", buckets.bad_messages()).unwrap();
+            writeln!(out, "total_messages: {}", buckets.total_messages()).unwrap();
+            writeln!(out, "truncated_packets: {}", buckets.truncated_packets()).unwrap();
+            writeln!(out, "dropped_packets: {}", buckets.dropped_packets()).unwrap();
+            writeln!(out, "queue_peak_depth: {}", buckets.queue_peak_depth()).unwrap();
+            writeln!(out, "flush_lateness_ms: {}", buckets.flush_lateness().as_millis()).unwrap();
+            writeln!(out, "renamed_keys: {}", buckets.renamed_keys()).unwrap();
+            writeln!(out, "rejected_keys: {}", buckets.rejected_keys()).unwrap();
+            writeln!(out, "reloads: {}", buckets.reloads()).unwrap();
+            writeln!(out, "failed_reloads: {}", buckets.failed_reloads()).unwrap();
+            if let Some(error) = buckets.last_reload_error() {
+                writeln!(out, "last_reload_error: {}", error).unwrap();
             }
-            "gauges" => {
-                for (key, value) in buckets.gauges().iter() {
-                    write!(out, " {}: {}\n", key, value).unwrap();
+            writeln!(out, "listeners: {}", buckets.listeners().len()).unwrap();
+            for (name, stats) in buckets.listeners().iter() {
+                write!(out,
+                       " {} - {}, restarts: {}, errors: {}",
+                       name,
+                       stats.state,
+                       stats.restarts,
+                       stats.errors)
+                    .unwrap();
+                if let Some(ref error) = stats.last_error {
+                    write!(out, ", last_error: {}", error).unwrap();
                 }
-                write!(out, "END\n\n").unwrap();
+                out.push('\n');
             }
-            "timers" => {
-                for (key, value) in buckets.timers().iter() {
-                    write!(out, " {}: {:?}\n", key, value).unwrap();
-                }
-                write!(out, "END\n\n").unwrap();
+            writeln!(out, "connections: {}", buckets.connections().len()).unwrap();
+            writeln!(out, "rejected_connections: {}", buckets.rejected_connections()).unwrap();
+            for stats in buckets.connections().values() {
+                writeln!(out,
+                         " {} - bytes: {}, messages: {}, bad_messages: {}",
+                         stats.peer,
+                         stats.bytes,
+                         stats.messages,
+                         stats.bad_messages)
+                    .unwrap();
             }
-            "quit" => {
-                write!(out, "Good bye!\n\n").unwrap();
-                done = true
+            write!(out, "END\n\n").unwrap();
+        }
+        "counters" => {
+            for (key, value) in buckets.counters().iter() {
+                writeln!(out, " {}: {}", key, value).unwrap();
             }
-            "clear" => {
-                buckets.reset();
-                write!(out, "Timers, counters and internal stats cleared.\n").unwrap();
+            write!(out, "END\n\n").unwrap();
+        }
+        "gauges" => {
+            for (key, value) in buckets.gauges().iter() {
+                writeln!(out, " {}: {}", key, value).unwrap();
             }
-            "" => {
-                // continue.
+            write!(out, "END\n\n").unwrap();
+        }
+        "timers" => {
+            for (key, value) in buckets.timers().iter() {
+                writeln!(out, " {}: {:?}", key, value).unwrap();
             }
-            x => {
-                write!(out, "ERROR - unknown command `{}`\n", x).unwrap();
+            write!(out, "END\n\n").unwrap();
+        }
+        "sets" => {
+            for (key, values) in buckets.sets().iter() {
+                writeln!(out, " {}: {}", key, values.len()).unwrap();
             }
+            write!(out, "END\n\n").unwrap();
         }
-        if out.len() > 0 {
-            let _ = writer.write(&out.as_bytes());
-            let _ = writer.flush();
+        "clear" => {
+            buckets.reset();
+            writeln!(out, "Timers, counters, sets and internal stats cleared.").unwrap();
         }
+        x => {
+            writeln!(out, "ERROR - unknown command `{}`", x).unwrap();
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use metric::{Metric, MetricKey, MetricKind};
+
+    #[test]
+    fn test_exec_counters() {
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("foo", 2.0, MetricKind::Counter(1.0)));
+        assert_eq!(" foo: 2\nEND\n\n", exec("counters", &mut buckets));
+    }
+
+    #[test]
+    fn test_exec_clear() {
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("foo", 2.0, MetricKind::Counter(1.0)));
+        exec("clear", &mut buckets);
+        assert_eq!(Some(&0.0), buckets.counters().get(&MetricKey::from("foo")));
+    }
+
+    #[test]
+    fn test_exec_unknown() {
+        let mut buckets = Buckets::new();
+        assert_eq!("ERROR - unknown command `quit`\n", exec("quit", &mut buckets));
     }
 }
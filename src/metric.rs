@@ -1,21 +1,31 @@
 /// Internal metric representation
 ///
+use std::borrow::Cow;
 use std::fmt;
 
 
 /// Enum of metric types
+#[derive(Clone, PartialEq)]
 pub enum MetricKind {
     Counter(f64), // sample rate
     Gauge,
-    Timer,
+    GaugeDelta,
+    Timer(f64), // sample rate
+    Histogram(f64), // sample rate
+    Distribution(f64), // sample rate
+    Set(String), // set member
 }
 
 impl fmt::Debug for MetricKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             MetricKind::Gauge => write!(f, "Gauge"),
-            MetricKind::Timer => write!(f, "Timer"),
+            MetricKind::GaugeDelta => write!(f, "GaugeDelta"),
+            MetricKind::Timer(s) => write!(f, "Timer(s={})", s),
+            MetricKind::Histogram(s) => write!(f, "Histogram(s={})", s),
+            MetricKind::Distribution(s) => write!(f, "Distribution(s={})", s),
             MetricKind::Counter(s) => write!(f, "Counter(s={})", s),
+            MetricKind::Set(ref m) => write!(f, "Set(m={})", m),
         }
     }
 }
@@ -23,20 +33,143 @@ impl fmt::Debug for MetricKind {
 
 /// Error types for parsing Metrics from strings.
 ///
-#[derive(Debug)]
+/// Errors record the line in the packet (starting at 1) and the
+/// byte column in that line (starting at 0) the problem was found at.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ParseError {
-    // Error message, column
-    SyntaxError(&'static str, usize),
+    // line, column
+    BadName(usize, usize),
+    BadValue(usize, usize),
+    BadSampleRate(usize, usize),
+    UnknownType(usize, usize),
+    TrailingData(usize, usize),
+    BadTimestamp(usize, usize),
+    // The packet did not contain any metrics.
+    Empty,
+}
+
+impl ParseError {
+    /// Get the line the error occurred on, 0 for empty packets.
+    pub fn line(&self) -> usize {
+        match *self {
+            ParseError::BadName(line, _) |
+            ParseError::BadValue(line, _) |
+            ParseError::BadSampleRate(line, _) |
+            ParseError::UnknownType(line, _) |
+            ParseError::TrailingData(line, _) |
+            ParseError::BadTimestamp(line, _) => line,
+            ParseError::Empty => 0,
+        }
+    }
+
+    /// Get the column the error occurred at.
+    pub fn column(&self) -> usize {
+        match *self {
+            ParseError::BadName(_, col) |
+            ParseError::BadValue(_, col) |
+            ParseError::BadSampleRate(_, col) |
+            ParseError::UnknownType(_, col) |
+            ParseError::TrailingData(_, col) |
+            ParseError::BadTimestamp(_, col) => col,
+            ParseError::Empty => 0,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            ParseError::BadName(..) => "Invalid metric name",
+            ParseError::BadValue(..) => "Invalid metric value",
+            ParseError::BadSampleRate(..) => "Invalid sample rate",
+            ParseError::UnknownType(..) => "Unknown metric type",
+            ParseError::TrailingData(..) => "Unexpected trailing data",
+            ParseError::BadTimestamp(..) => "Invalid timestamp",
+            ParseError::Empty => return write!(f, "No metrics found"),
+        };
+        write!(f, "{} at line {}, column {}", msg, self.line(), self.column())
+    }
+}
+
+
+/// A list of `(key, value)` tag pairs.
+///
+/// Tags without a value (e.g. `#canary`) have an empty value.
+pub type Tags = Vec<(String, String)>;
+
+
+/// The identity metrics are aggregated on.
+///
+/// Made of the metric name and its tags in a canonical sorted
+/// order so that `#a:1,b:2` and `#b:2,a:1` share a bucket.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MetricKey {
+    pub name: String,
+    pub tags: Tags,
+}
+
+impl MetricKey {
+    /// Create a new key, sorting and de-duplicating the tags.
+    pub fn new<S: Into<String>>(name: S, tags: &[(String, String)]) -> MetricKey {
+        let mut tags = tags.to_vec();
+        tags.sort();
+        tags.dedup();
+        MetricKey {
+            name: name.into(),
+            tags,
+        }
+    }
+
+    /// Create a key for a derived value, e.g. `foo.min` from `foo`.
+    ///
+    /// The tags of the original key are kept.
+    pub fn suffixed(&self, suffix: &str) -> MetricKey {
+        MetricKey {
+            name: format!("{}.{}", self.name, suffix),
+            tags: self.tags.clone(),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for MetricKey {
+    fn from(name: &'a str) -> MetricKey {
+        MetricKey::new(name, &[])
+    }
+}
+
+/// Keys are displayed as `name` or `name [key=value,key2=value2]`
+impl fmt::Display for MetricKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if self.tags.is_empty() {
+            return Ok(());
+        }
+        write!(f, " [")?;
+        for (i, (key, value)) in self.tags.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if value.is_empty() {
+                write!(f, "{}", key)?;
+            } else {
+                write!(f, "{}={}", key, value)?;
+            }
+        }
+        write!(f, "]")
+    }
 }
 
 
 /// Metric value objects.
 ///
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Metric {
     pub kind: MetricKind,
     pub name: String,
     pub value: f64,
+    pub tags: Tags,
+    // Client supplied unix timestamp.
+    pub timestamp: Option<i64>,
 }
 
 impl Metric {
@@ -46,86 +179,307 @@ impl Metric {
     pub fn new<S: Into<String>>(name: S, value: f64, kind: MetricKind) -> Metric {
         Metric {
             name: name.into(),
-            value: value,
-            kind: kind,
+            value,
+            kind,
+            tags: Vec::new(),
+            timestamp: None,
         }
     }
 
+    /// Set the client supplied timestamp of a metric.
+    pub fn with_timestamp(mut self, timestamp: i64) -> Metric {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Replace the tags on a metric.
+    pub fn with_tags(mut self, tags: Tags) -> Metric {
+        self.tags = tags;
+        self
+    }
+
+    /// Get the key this metric is aggregated on.
+    pub fn key(&self) -> MetricKey {
+        MetricKey::new(self.name.as_str(), &self.tags)
+    }
+
     /// Valid message formats are:
     ///
     /// - `<str:metric_name>:<f64:value>|<str:type>`
     /// - `<str:metric_name>:<f64:value>|c|@<f64:sample_rate>`
+    /// - `<str:metric_name>:<f64:value>|ms|@<f64:sample_rate>`
+    /// - `<str:metric_name>:<f64:value>|h|@<f64:sample_rate>`
+    /// - `<str:metric_name>:<f64:value>|d|@<f64:sample_rate>`
+    /// - `<str:metric_name>:<+-f64:delta>|g`
+    /// - `<str:metric_name>:<str:member>|s`
+    /// - `<str:metric_name>:<f64:value>|<str:type>|#<str:tag>:<str:value>,<str:tag>`
+    /// - `<str:metric_name>:<f64:value>|<str:type>:<f64:value>|<str:type>`
+    /// - `<str:metric_name>:<f64:value>|<c|g>|T<i64:unix_timestamp>`
+    /// - `<str:metric_name>,<str:tag>=<str:value>:<f64:value>|<str:type>`
     ///
     /// Multiple metrics can be sent in a single UDP packet
     /// separated by newlines.
+    ///
+    /// Parsing never panics, any malformed input results in a `ParseError`.
     pub fn parse(source: &str) -> Result<Vec<Metric>, ParseError> {
         let mut results: Vec<Metric> = Vec::new();
 
-        for line in source.lines() {
-            match Metric::parse_line(line) {
+        for result in Metric::parse_lines(source) {
+            match result {
                 Ok(metric) => results.push(metric),
                 Err(e) => return Err(e),
             }
         }
-        if results.len() == 0 {
-            return Err(ParseError::SyntaxError("No metrics found", 0));
+        if results.is_empty() {
+            return Err(ParseError::Empty);
         }
         Ok(results)
     }
 
-    /// Parses a metric from each line in a packet.
-    fn parse_line(line: &str) -> Result<Metric, ParseError> {
-        // track position in string
-        let mut idx = 0;
+    /// Parse each line in a packet independently.
+    ///
+    /// Unlike `parse` a bad line doesn't cause the other lines to
+    /// be dropped, a result is returned for each non-blank line.
+    pub fn parse_lines(source: &str) -> Vec<Result<Metric, ParseError>> {
+        source.lines()
+              .enumerate()
+              .filter(|&(_, line)| !line.is_empty())
+              .flat_map(|(i, line)| Metric::parse_line(line, i + 1))
+              .collect()
+    }
 
-        // Get the metric name
-        let name = match line.find(':') {
-            Some(pos) => {
-                idx = pos + 1;
-                &line[0..pos]
-            }
-            _ => "",
+    /// Parses the metrics from each line in a packet.
+    ///
+    /// A line can contain several values for the same name
+    /// e.g. `latency:12|ms:15|ms`, each value is parsed separately.
+    /// `line_no` is only used for error reporting.
+    pub fn parse_line(line: &str, line_no: usize) -> Vec<Result<Metric, ParseError>> {
+        // Get the metric name, which must be followed by a value.
+        let name_end = match line.find(':') {
+            Some(pos) => pos,
+            None => return vec![Err(ParseError::BadName(line_no, line.len()))],
         };
+        // Telegraf style tags can follow the name e.g. `name,host=web1`
+        let mut name_parts = line[..name_end].splitn(2, ',');
+        let name = name_parts.next().unwrap_or("");
         if name.is_empty() {
-            return Err(ParseError::SyntaxError("Metrics require a name.", idx));
+            return vec![Err(ParseError::BadName(line_no, 0))];
         }
+        let name_tags = match name_parts.next() {
+            Some(raw) => Metric::parse_name_tags(raw),
+            None => Vec::new(),
+        };
+
+        Metric::split_values(line, name_end + 1)
+            .into_iter()
+            .map(|(start, end)| {
+                Metric::parse_value(name, &name_tags, &line[..end], start, line_no)
+            })
+            .collect()
+    }
 
-        // Get the float val
-        let value = match line[idx..].find('|') {
-            Some(pos) => {
-                let start = idx;
-                idx += pos + 1;
-                line[start..idx - 1].parse::<f64>().ok().unwrap()
+    /// Find the `(start, end)` offsets of each value in a line.
+    ///
+    /// Values are separated by `:`, except within a `|#tags` field
+    /// where `:` separates tag names and values. This means a tags field
+    /// ends the line.
+    fn split_values(line: &str, start: usize) -> Vec<(usize, usize)> {
+        let bytes = line.as_bytes();
+        let mut values = Vec::new();
+        let mut value_start = start;
+        let mut in_tags = false;
+        for i in start..bytes.len() {
+            match bytes[i] {
+                b'|' => in_tags = bytes.get(i + 1) == Some(&b'#'),
+                b':' if !in_tags => {
+                    values.push((value_start, i));
+                    value_start = i + 1;
+                }
+                _ => {}
             }
-            _ => return Err(ParseError::SyntaxError("Metrics require a value.", idx)),
-        };
-        let kind_name = match line[idx..].find('|') {
-            Some(pos) => {
-                let start = idx;
-                idx += pos;
-                line[start..idx].to_string()
+        }
+        values.push((value_start, line.len()));
+        values
+    }
+
+    /// Parses a single `value|type|...` section of a line starting at `value_start`.
+    ///
+    /// `line` ends where the value section does, offsets are kept relative
+    /// to the start of the line for error reporting.
+    fn parse_value(name: &str,
+                   name_tags: &[(String, String)],
+                   line: &str,
+                   value_start: usize,
+                   line_no: usize)
+                   -> Result<Metric, ParseError> {
+        // Get the raw value, it is converted once the type is known.
+        let value_end = match line[value_start..].find('|') {
+            Some(pos) => value_start + pos,
+            None if value_start == line.len() => {
+                return Err(ParseError::BadValue(line_no, value_start))
             }
-            _ => line[idx..].to_string(),
+            None => return Err(ParseError::UnknownType(line_no, line.len())),
         };
+        let value = &line[value_start..value_end];
+        if value.is_empty() {
+            return Err(ParseError::BadValue(line_no, value_start));
+        }
 
-        // Get kind parts, use deref/ref tricks
-        // to get types to match
-        let kind = match &*kind_name {
-            "ms" => MetricKind::Timer,
-            "g" => MetricKind::Gauge,
-            "c" => {
-                let rate: f64 = match line[idx..].find('@') {
-                    Some(pos) => {
-                        idx += pos + 1;
-                        line[idx..].parse::<f64>().ok().unwrap()
-                    }
-                    _ => 1.0,
+        // The type is followed by optional `|@rate`, `|#tags` and `|T<timestamp>` fields.
+        let kind_start = value_end + 1;
+        let mut fields = line[kind_start..].split('|');
+        let kind_name = fields.next().unwrap_or("");
+
+        let mut idx = kind_start + kind_name.len();
+        let mut rate = 1.0;
+        let mut tags = name_tags.to_vec();
+        let mut timestamp = None;
+        for field in fields {
+            idx += 1;
+            if let Some(raw) = field.strip_prefix('@') {
+                rate = match raw.parse::<f64>() {
+                    Ok(r) if r.is_finite() && r > 0.0 => r,
+                    _ => return Err(ParseError::BadSampleRate(line_no, idx)),
+                };
+            } else if let Some(raw) = field.strip_prefix('#') {
+                tags.extend(Metric::parse_tags(raw));
+            } else if let Some(raw) = field.strip_prefix('T') {
+                timestamp = match raw.parse::<i64>() {
+                    // Only counters and gauges can be sent with a timestamp.
+                    Ok(ts) if ts > 0 && (kind_name == "c" || kind_name == "g") => Some(ts),
+                    _ => return Err(ParseError::BadTimestamp(line_no, idx)),
                 };
-                MetricKind::Counter(rate)
+            } else if !field.is_empty() {
+                return Err(ParseError::TrailingData(line_no, idx));
             }
-            _ => return Err(ParseError::SyntaxError("Unknown metric type.", idx)),
+            idx += field.len();
+        }
+
+        let kind = match kind_name {
+            "s" => {
+                let metric = Metric::new(name, 0.0, MetricKind::Set(value.to_string()));
+                return Ok(metric.with_tags(tags));
+            }
+            "ms" => MetricKind::Timer(rate),
+            "h" => MetricKind::Histogram(rate),
+            "d" => MetricKind::Distribution(rate),
+            // Signed values adjust the previous gauge value.
+            "g" if value.starts_with('+') || value.starts_with('-') => MetricKind::GaugeDelta,
+            "g" => MetricKind::Gauge,
+            "c" => MetricKind::Counter(rate),
+            _ => return Err(ParseError::UnknownType(line_no, kind_start)),
+        };
+        let value = match value.parse::<f64>() {
+            Ok(v) if v.is_finite() => v,
+            _ => return Err(ParseError::BadValue(line_no, value_start)),
         };
-        Ok(Metric::new(name, value, kind))
+        let metric = Metric::new(name, value, kind).with_tags(tags);
+        Ok(match timestamp {
+            Some(ts) => metric.with_timestamp(ts),
+            None => metric,
+        })
+    }
+
+    /// Parses a Telegraf/InfluxDB tag list like `host=web1,code=200`.
+    fn parse_name_tags(source: &str) -> Tags {
+        source.split(',')
+              .filter(|tag| !tag.is_empty())
+              .map(|tag| match tag.find('=') {
+                  Some(pos) => (tag[..pos].to_string(), tag[pos + 1..].to_string()),
+                  None => (tag.to_string(), String::new()),
+              })
+              .collect()
+    }
+
+    /// Parses a DogStatsD tag list like `env:prod,canary`.
+    pub fn parse_tags(source: &str) -> Tags {
+        source.split(',')
+              .filter(|tag| !tag.is_empty())
+              .map(|tag| match tag.find(':') {
+                  Some(pos) => (tag[..pos].to_string(), tag[pos + 1..].to_string()),
+                  None => (tag.to_string(), String::new()),
+              })
+              .collect()
+    }
+}
+
+
+/// Formats a metric in the statsd line protocol.
+///
+/// The output can be parsed by `Metric::parse` back into an equal
+/// metric, with some exceptions because not every metric can be written:
+///
+/// - `:`, `|`, `,` and line breaks in names, set members and tags
+///   are replaced with `_` as they would end the field.
+/// - Timestamps are only written for counters and gauges, the only
+///   kinds that accept them.
+/// - Gauges can't be set to a negative value in a single line, so they
+///   are written as a reset to 0 followed by a delta on a second line.
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (kind, rate) = match self.kind {
+            MetricKind::Counter(rate) => ("c", rate),
+            MetricKind::Timer(rate) => ("ms", rate),
+            MetricKind::Histogram(rate) => ("h", rate),
+            MetricKind::Distribution(rate) => ("d", rate),
+            MetricKind::Gauge | MetricKind::GaugeDelta | MetricKind::Set(_) => {
+                ("", 1.0)
+            }
+        };
+        let name = clean_field(&self.name);
+        match self.kind {
+            MetricKind::Set(ref member) => write!(f, "{}:{}|s", name, clean_field(member))?,
+            MetricKind::GaugeDelta => write!(f, "{}:{:+}|g", name, self.value)?,
+            MetricKind::Gauge if self.value < 0.0 => {
+                write!(f, "{}:0|g", name)?;
+                self.fmt_suffix(f)?;
+                write!(f, "\n{}:{}|g", name, self.value)?;
+            }
+            // abs() avoids writing -0 which would be read as a delta.
+            MetricKind::Gauge => write!(f, "{}:{}|g", name, self.value.abs())?,
+            _ => write!(f, "{}:{}|{}", name, self.value, kind)?,
+        }
+        if rate != 1.0 {
+            write!(f, "|@{}", rate)?;
+        }
+        self.fmt_suffix(f)
+    }
+}
+
+impl Metric {
+    /// Write the tags and timestamp fields of a metric line.
+    fn fmt_suffix(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.tags.is_empty() {
+            write!(f, "|#")?;
+            for (i, (key, value)) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                if value.is_empty() {
+                    write!(f, "{}", clean_field(key))?;
+                } else {
+                    write!(f, "{}:{}", clean_field(key), clean_field(value))?;
+                }
+            }
+        }
+        let timestamped = matches!(self.kind,
+                                   MetricKind::Counter(_) |
+                                   MetricKind::Gauge |
+                                   MetricKind::GaugeDelta);
+        if let (Some(ts), true) = (self.timestamp, timestamped) {
+            write!(f, "|T{}", ts)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replace the characters that separate fields in a metric line.
+fn clean_field(field: &str) -> Cow<'_, str> {
+    let separator = |c: char| matches!(c, ':' | '|' | ',' | '\n' | '\r');
+    if field.contains(separator) {
+        Cow::Owned(field.replace(separator, "_"))
+    } else {
+        Cow::Borrowed(field)
     }
 }
 
@@ -135,17 +489,23 @@ impl Metric {
 //
 #[cfg(test)]
 mod tests {
-    use metric::{Metric, MetricKind};
+    use metric::{Metric, MetricKey, MetricKind, ParseError};
     use std::collections::HashMap;
+    use proptest::prelude::*;
 
     #[test]
     fn test_metric_kind_debug_fmt() {
         assert_eq!("Gauge", format!("{:?}", MetricKind::Gauge));
-        assert_eq!("Timer", format!("{:?}", MetricKind::Timer));
+        assert_eq!("GaugeDelta", format!("{:?}", MetricKind::GaugeDelta));
+        assert_eq!("Timer(s=0.5)", format!("{:?}", MetricKind::Timer(0.5)));
+        assert_eq!("Histogram(s=1)", format!("{:?}", MetricKind::Histogram(1.0)));
+        assert_eq!("Distribution(s=1)", format!("{:?}", MetricKind::Distribution(1.0)));
         assert_eq!("Counter(s=6)", format!("{:?}", MetricKind::Counter(6.0)));
+        assert_eq!("Set(m=bob)", format!("{:?}", MetricKind::Set("bob".to_string())));
     }
 
     #[test]
+    #[allow(clippy::nonminimal_bool)]
     fn test_metric_parse_invalid_no_name() {
         let res = Metric::parse("");
         assert!(res.is_err(), "Should have an error");
@@ -153,6 +513,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::nonminimal_bool)]
     fn test_metric_parse_invalid_no_value() {
         let res = Metric::parse("foo:");
         assert!(res.is_err(), "Should have an error");
@@ -175,20 +536,36 @@ mod tests {
     fn test_metric_valid() {
         let mut valid = HashMap::new();
         valid.insert("foo.test:12.3|ms\n",
-                     Metric::new("foo.test", 12.3, MetricKind::Timer));
+                     Metric::new("foo.test", 12.3, MetricKind::Timer(1.0)));
         valid.insert("foo.test:12.3|ms",
-                     Metric::new("foo.test", 12.3, MetricKind::Timer));
+                     Metric::new("foo.test", 12.3, MetricKind::Timer(1.0)));
         valid.insert("test:18.123|g",
                      Metric::new("test", 18.123, MetricKind::Gauge));
         valid.insert("test:18.123|g",
                      Metric::new("test", 18.123, MetricKind::Gauge));
+        valid.insert("foo.test:12|ms|@0.1",
+                     Metric::new("foo.test", 12.0, MetricKind::Timer(0.1)));
+        valid.insert("request.size:512|h",
+                     Metric::new("request.size", 512.0, MetricKind::Histogram(1.0)));
+        valid.insert("request.size:512|h|@0.5",
+                     Metric::new("request.size", 512.0, MetricKind::Histogram(0.5)));
+        valid.insert("request.size:12.5|d",
+                     Metric::new("request.size", 12.5, MetricKind::Distribution(1.0)));
+        valid.insert("gaugor:+3|g",
+                     Metric::new("gaugor", 3.0, MetricKind::GaugeDelta));
+        valid.insert("gaugor:-10.5|g",
+                     Metric::new("gaugor", -10.5, MetricKind::GaugeDelta));
         valid.insert("thing.total:12|c",
                      Metric::new("thing.total", 12.0, MetricKind::Counter(1.0)));
         valid.insert("thing.total:5.6|c|@123",
                      Metric::new("thing.total", 5.6, MetricKind::Counter(123.0)));
+        valid.insert("users.unique:765|s",
+                     Metric::new("users.unique", 0.0, MetricKind::Set("765".to_string())));
+        valid.insert("users.unique:bob|s",
+                     Metric::new("users.unique", 0.0, MetricKind::Set("bob".to_string())));
 
         for (input, expected) in valid.iter() {
-            let result = Metric::parse(*input);
+            let result = Metric::parse(input);
             assert!(result.is_ok());
 
             let actual = result.ok().unwrap();
@@ -201,20 +578,279 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_metric_tags() {
+        let res = Metric::parse("foo:1|c|#env:prod,canary,region:eu").unwrap();
+        assert_eq!("foo", res[0].name);
+        assert_eq!(vec![("env".to_string(), "prod".to_string()),
+                        ("canary".to_string(), "".to_string()),
+                        ("region".to_string(), "eu".to_string())],
+                   res[0].tags);
+
+        let res = Metric::parse("foo:1|c|@0.5|#env:prod").unwrap();
+        assert_eq!(1, res[0].tags.len());
+        assert_eq!("Counter(s=0.5)", format!("{:?}", res[0].kind));
+
+        let res = Metric::parse("foo:1|s|#env:prod").unwrap();
+        assert_eq!(1, res[0].tags.len());
+    }
+
+    #[test]
+    fn test_metric_timestamp() {
+        let res = Metric::parse("foo:1|g|T1700000000\nfoo:2|c|@0.5|#a:b|T1700000001\nfoo:3|c")
+                      .unwrap();
+        assert_eq!(Some(1700000000), res[0].timestamp);
+        assert_eq!(Some(1700000001), res[1].timestamp);
+        assert_eq!(1, res[1].tags.len());
+        assert_eq!(None, res[2].timestamp);
+    }
+
+    #[test]
+    fn test_metric_name_tags() {
+        let res = Metric::parse("requests,host=web1,code=200:1|c").unwrap();
+        assert_eq!("requests", res[0].name);
+        assert_eq!(vec![("host".to_string(), "web1".to_string()),
+                        ("code".to_string(), "200".to_string())],
+                   res[0].tags);
+
+        // Both styles of tags share the same representation.
+        let dog = Metric::parse("requests:1|c|#code:200,host:web1").unwrap();
+        assert_eq!(dog[0].key(), res[0].key());
+
+        let res = Metric::parse("requests,host=web1:1|c:2|c|#env:prod").unwrap();
+        assert_eq!(1, res[0].tags.len());
+        assert_eq!(2, res[1].tags.len());
+
+        let res = Metric::parse("requests.total:1|c").unwrap();
+        assert_eq!("requests.total", res[0].name);
+        assert!(res[0].tags.is_empty());
+
+        assert_eq!(Some(ParseError::BadName(1, 0)), Metric::parse(",host=web1:1|c").err());
+    }
+
+    #[test]
+    fn test_metric_key_canonical() {
+        let a = Metric::parse("foo:1|c|#b:2,a:1").unwrap();
+        let b = Metric::parse("foo:1|c|#a:1,b:2,a:1").unwrap();
+        assert_eq!(a[0].key(), b[0].key());
+        assert_eq!("foo [a=1,b=2]", format!("{}", a[0].key()));
+        assert_eq!("foo", format!("{}", MetricKey::from("foo")));
+        assert_eq!("foo.min [a=1,b=2]", format!("{}", a[0].key().suffixed("min")));
+    }
+
     #[test]
     fn test_metric_invalid() {
-        let invalid = vec!["",
-                           "metric",
-                           "metric|11:",
-                           "metric|12",
-                           "metric:13|",
-                           "metric:14|c@1",
-                           ":|@",
-                           ":1.0|c"];
+        let invalid = ["",
+                       "metric",
+                       "metric|11:",
+                       "metric|12",
+                       "metric:13|",
+                       "metric:14|c@1",
+                       ":|@",
+                       "metric:|s",
+                       "metric:1|c|env:prod",
+                       "foo:abc|c",
+                       "foo:nan|g",
+                       "foo:inf|ms",
+                       "foo:1|c|@abc",
+                       "foo:1|c|@0",
+                       "foo:1|c|@-1",
+                       "foo:1|c|@",
+                       ":1.0|c"];
         for input in invalid.iter() {
             println!("{:?}", input);
-            let result = Metric::parse(*input);
+            let result = Metric::parse(input);
             assert!(result.is_err());
         }
     }
+
+    #[test]
+    fn test_metric_parse_error_kinds() {
+        let cases = vec![("", ParseError::Empty),
+                         (":1|c", ParseError::BadName(1, 0)),
+                         ("foo", ParseError::BadName(1, 3)),
+                         ("foo,host=web1", ParseError::BadName(1, 13)),
+                         ("foo:", ParseError::BadValue(1, 4)),
+                         ("foo:|c", ParseError::BadValue(1, 4)),
+                         ("foo:abc|c", ParseError::BadValue(1, 4)),
+                         ("foo:1", ParseError::UnknownType(1, 5)),
+                         ("foo:1|x", ParseError::UnknownType(1, 6)),
+                         ("foo:1|c|@x", ParseError::BadSampleRate(1, 8)),
+                         ("foo:1|c|#a:b|junk", ParseError::TrailingData(1, 13)),
+                         ("foo:1|c|Tabc", ParseError::BadTimestamp(1, 8)),
+                         ("foo:1|ms|T1700000000", ParseError::BadTimestamp(1, 9)),
+                         ("foo:1|c\nbar:1|c\nbaz:1|q", ParseError::UnknownType(3, 6))];
+        for (input, expected) in cases {
+            let result = Metric::parse(input);
+            assert_eq!(Some(expected), result.err(), "input {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_metric_parse_lines() {
+        let res = Metric::parse_lines("a.b:1|c\nbad\n\nb.c:2|g\nc.d:x|ms");
+        assert_eq!(4, res.len());
+        assert_eq!("a.b", res[0].as_ref().unwrap().name);
+        assert_eq!(Some(ParseError::BadName(2, 3)), res[1].as_ref().err().cloned());
+        assert_eq!("b.c", res[2].as_ref().unwrap().name);
+        assert_eq!(Some(ParseError::BadValue(5, 4)), res[3].as_ref().err().cloned());
+
+        assert_eq!(0, Metric::parse_lines("").len());
+        assert_eq!(0, Metric::parse_lines("\n\n").len());
+    }
+
+    #[test]
+    fn test_metric_multiple_values() {
+        let res = Metric::parse("latency:12|ms:15|ms|@0.5:9|ms").unwrap();
+        assert_eq!(3, res.len());
+        assert!(res.iter().all(|m| m.name == "latency"));
+        assert_eq!(vec![12.0, 15.0, 9.0],
+                   res.iter().map(|m| m.value).collect::<Vec<f64>>());
+        assert_eq!("Timer(s=0.5)", format!("{:?}", res[1].kind));
+
+        // Tags are the last field in a line, so they belong to the last value.
+        let res = Metric::parse("foo:1|c:2|c|#env:prod,a:b").unwrap();
+        assert_eq!(2, res.len());
+        assert_eq!(0, res[0].tags.len());
+        assert_eq!(2, res[1].tags.len());
+    }
+
+    #[test]
+    fn test_metric_multiple_values_partial() {
+        let res = Metric::parse_lines("foo:1|c:x|c:3|g");
+        assert_eq!(3, res.len());
+        assert_eq!(1.0, res[0].as_ref().unwrap().value);
+        assert_eq!(Some(ParseError::BadValue(1, 8)), res[1].as_ref().err().cloned());
+        assert_eq!(3.0, res[2].as_ref().unwrap().value);
+    }
+
+    #[test]
+    fn test_metric_parse_error_display() {
+        assert_eq!("Invalid metric value at line 2, column 4",
+                   format!("{}", ParseError::BadValue(2, 4)));
+        assert_eq!("No metrics found", format!("{}", ParseError::Empty));
+    }
+
+    #[test]
+    fn test_metric_display() {
+        let tags = vec![("env".to_string(), "prod".to_string()), ("canary".to_string(), String::new())];
+        let cases = vec![(Metric::new("foo", 1.5, MetricKind::Counter(1.0)), "foo:1.5|c"),
+                         (Metric::new("foo", 2.0, MetricKind::Counter(0.1)), "foo:2|c|@0.1"),
+                         (Metric::new("foo", 12.0, MetricKind::Timer(1.0)), "foo:12|ms"),
+                         (Metric::new("foo", 3.0, MetricKind::Histogram(0.5)), "foo:3|h|@0.5"),
+                         (Metric::new("foo", 3.0, MetricKind::Distribution(1.0)), "foo:3|d"),
+                         (Metric::new("foo", 7.0, MetricKind::Gauge), "foo:7|g"),
+                         (Metric::new("foo", 4.0, MetricKind::GaugeDelta), "foo:+4|g"),
+                         (Metric::new("foo", -4.0, MetricKind::GaugeDelta), "foo:-4|g"),
+                         (Metric::new("foo", 0.0, MetricKind::Set("bob".to_string())), "foo:bob|s"),
+                         (Metric::new("foo", 1.0, MetricKind::Counter(1.0)).with_tags(tags),
+                          "foo:1|c|#env:prod,canary"),
+                         (Metric::new("foo", 1.0, MetricKind::Gauge).with_timestamp(1700000000),
+                          "foo:1|g|T1700000000")];
+        for (metric, expected) in cases {
+            assert_eq!(expected, metric.to_string());
+            assert_eq!(vec![metric.clone()], Metric::parse(&metric.to_string()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_metric_display_lossy() {
+        let tags = vec![("a,b".to_string(), "c|d".to_string())];
+        let cases = vec![(Metric::new("foo:bar|c", 1.0, MetricKind::Counter(1.0)), "foo_bar_c:1|c"),
+                         (Metric::new("foo\nbar", 1.0, MetricKind::Timer(1.0)), "foo_bar:1|ms"),
+                         (Metric::new("foo", 0.0, MetricKind::Set("a:b,c".to_string())),
+                          "foo:a_b_c|s"),
+                         (Metric::new("foo", 1.0, MetricKind::Counter(1.0)).with_tags(tags),
+                          "foo:1|c|#a_b:c_d"),
+                         (Metric::new("foo", 5.0, MetricKind::Timer(1.0)).with_timestamp(1700000000),
+                          "foo:5|ms")];
+        for (metric, expected) in cases {
+            assert_eq!(expected, metric.to_string());
+            assert_eq!(1, Metric::parse(&metric.to_string()).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn test_metric_display_negative_gauge() {
+        let metric = Metric::new("foo", -7.0, MetricKind::Gauge).with_timestamp(1700000000);
+        assert_eq!("foo:0|g|T1700000000\nfoo:-7|g|T1700000000", metric.to_string());
+
+        let parsed = Metric::parse(&metric.to_string()).unwrap();
+        assert_eq!(2, parsed.len());
+        assert_eq!(MetricKind::Gauge, parsed[0].kind);
+        assert_eq!(0.0, parsed[0].value);
+        assert_eq!(MetricKind::GaugeDelta, parsed[1].kind);
+        assert_eq!(-7.0, parsed[1].value);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_parse_never_panics(input in "\\PC*") {
+            let _ = Metric::parse(&input);
+        }
+
+        #[test]
+        fn prop_parse_never_panics_on_metric_like(input in "[a-z.:|@#,0-9+\\-e\\n]{0,40}") {
+            let _ = Metric::parse(&input);
+        }
+
+        #[test]
+        fn prop_parse_error_position_in_line(input in "[a-z.:|@#,0-9+\\-\\n]{0,40}") {
+            if let Err(err) = Metric::parse(&input) {
+                if err != ParseError::Empty {
+                    let line = input.lines().nth(err.line() - 1).unwrap();
+                    prop_assert!(err.column() <= line.len());
+                }
+            }
+        }
+
+        #[test]
+        fn prop_parse_valid_lines(name in "[a-zA-Z_][a-zA-Z0-9_.]{0,20}",
+                                  value in -1.0e9f64..1.0e9f64,
+                                  kind in prop::sample::select(vec!["c", "ms", "g"])) {
+            let line = format!("{}:{}|{}", name, value, kind);
+            let metrics = Metric::parse(&line).unwrap();
+            prop_assert_eq!(1, metrics.len());
+            prop_assert_eq!(&name, &metrics[0].name);
+            prop_assert_eq!(value, metrics[0].value);
+        }
+
+        #[test]
+        fn prop_display_round_trips(name in "[a-zA-Z_][a-zA-Z0-9_.]{0,20}",
+                                    value in -1.0e9f64..1.0e9f64,
+                                    rate in prop::sample::select(vec![1.0, 0.5, 0.01]),
+                                    kind in 0..7,
+                                    member in "[a-z0-9.]{1,5}",
+                                    timestamp in prop::option::of(1i64..2_000_000_000),
+                                    tags in prop::collection::vec(("[a-z]{1,5}", "[a-z0-9]{0,5}"), 0..3)) {
+            let (kind, value) = match kind {
+                0 => (MetricKind::Counter(rate), value),
+                1 => (MetricKind::Timer(rate), value),
+                2 => (MetricKind::Histogram(rate), value),
+                3 => (MetricKind::Distribution(rate), value),
+                4 => (MetricKind::Gauge, value),
+                5 => (MetricKind::GaugeDelta, value),
+                _ => (MetricKind::Set(member), 0.0),
+            };
+            let mut metric = Metric::new(name, value, kind).with_tags(tags);
+            metric.timestamp = timestamp;
+            let parsed = Metric::parse(&metric.to_string()).unwrap();
+
+            // Only counters and gauges keep their timestamp.
+            let mut expected = metric.clone();
+            if !matches!(expected.kind,
+                         MetricKind::Counter(_) | MetricKind::Gauge | MetricKind::GaugeDelta) {
+                expected.timestamp = None;
+            }
+            if expected.kind == MetricKind::Gauge && value < 0.0 {
+                // Written as a reset followed by a delta.
+                let mut reset = expected.clone();
+                reset.value = 0.0;
+                expected.kind = MetricKind::GaugeDelta;
+                prop_assert_eq!(vec![reset, expected], parsed);
+            } else {
+                prop_assert_eq!(vec![expected], parsed);
+            }
+        }
+    }
 }
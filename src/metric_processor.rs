@@ -1,5 +1,5 @@
 use super::buckets::Buckets;
-use super::metric::{Metric, MetricKind};
+use super::metric::{Metric, MetricKey, MetricKind};
 use std::collections::HashMap;
 use time;
 
@@ -7,15 +7,50 @@ use time;
 ///
 /// Creates:
 ///
-/// - timer percentile data.
+/// - timer, histogram and distribution percentile data.
+/// - sample rate adjusted counts and per second rates.
 /// - internal processing metrics
 pub fn process(buckets: &mut Buckets) {
     let start_time = time::get_time();
+    let interval = buckets.flush_interval() as f64;
+
+    let timer_data = summarize(buckets.timers(), buckets.timer_counts(), interval);
+    buckets.set_timer_data(timer_data);
+
+    let histogram_data = summarize(buckets.histograms(), buckets.histogram_counts(), interval);
+    buckets.set_histogram_data(histogram_data);
+
+    let distribution_data = summarize(buckets.distributions(),
+                                      buckets.distribution_counts(),
+                                      interval);
+    buckets.set_distribution_data(distribution_data);
+
+    let duration = time::get_time() - start_time;
+    let process_duration = Metric::new("statsd.processing_time",
+                                       duration.num_milliseconds() as f64,
+                                       MetricKind::Counter(1.0));
+    buckets.add(&process_duration);
+}
 
-    let mut timer_data = HashMap::new();
 
-    // Add the various derived values for timers.
-    for (key, values) in buckets.timers().iter() {
+/// Calculate the derived values for a timer like series.
+fn summarize(series: &HashMap<MetricKey, Vec<f64>>,
+             counts: &HashMap<MetricKey, f64>,
+             interval: f64)
+             -> HashMap<MetricKey, f64> {
+    let mut data = HashMap::new();
+
+    for (key, values) in series.iter() {
+        // Counts are scaled by the sample rate of each value.
+        let count = *counts.get(key).unwrap_or(&0.0);
+        data.insert(key.suffixed("count"), count);
+        data.insert(key.suffixed("count_ps"), count / interval);
+
+        // Series with no values since the last flush only report counts.
+        if values.is_empty() {
+            continue;
+        }
+
         let mut v = values.clone();
         v.sort_by(|a, b| a.partial_cmp(b).unwrap());
 
@@ -30,34 +65,27 @@ pub fn process(buckets: &mut Buckets) {
         let median = percentile(&v, 0.5);
         let upper_95 = percentile(&v, 0.95);
 
-        timer_data.insert(format!("{}.min", key), v[0]);
-        timer_data.insert(format!("{}.max", key), v[v.len() - 1]);
-        timer_data.insert(format!("{}.count", key), len);
-        timer_data.insert(format!("{}.mean", key), mean);
-        timer_data.insert(format!("{}.median", key), median);
-        timer_data.insert(format!("{}.stddev", key), stddev);
-        timer_data.insert(format!("{}.upper_95", key), upper_95);
+        data.insert(key.suffixed("min"), v[0]);
+        data.insert(key.suffixed("max"), v[v.len() - 1]);
+        data.insert(key.suffixed("mean"), mean);
+        data.insert(key.suffixed("median"), median);
+        data.insert(key.suffixed("stddev"), stddev);
+        data.insert(key.suffixed("upper_95"), upper_95);
     }
-    buckets.set_timer_data(timer_data);
-
-    let duration = time::get_time() - start_time;
-    let process_duration = Metric::new("statsd.processing_time",
-                                       duration.num_milliseconds() as f64,
-                                       MetricKind::Counter(1.0));
-    buckets.add(&process_duration);
+    data
 }
 
 
 /// Extract the value at the given percentile.
 /// If vector has an even length, two values will be
 /// averaged together.
-fn percentile(values: &Vec<f64>, tile: f64) -> f64 {
+fn percentile(values: &[f64], tile: f64) -> f64 {
     let len = values.len() as f64;
     let index = (len * tile) as usize;
-    if (values.len() % 2) == 0 {
-        return (values[index - 1] + values[index]) / 2.0;
+    if values.len().is_multiple_of(2) {
+        (values[index - 1] + values[index]) / 2.0
     } else {
-        return values[index];
+        values[index]
     }
 }
 
@@ -67,17 +95,17 @@ fn percentile(values: &Vec<f64>, tile: f64) -> f64 {
 mod test {
     use super::*;
     use super::super::buckets::Buckets;
-    use super::super::metric::{Metric, MetricKind};
+    use super::super::metric::{Metric, MetricKey, MetricKind};
 
     fn make_buckets() -> Buckets {
         let mut buckets = Buckets::new();
 
-        let metrics = [Metric::new("some.timer", 13.1, MetricKind::Timer),
-                       Metric::new("some.timer", 33.7, MetricKind::Timer),
-                       Metric::new("some.timer", 3.4, MetricKind::Timer),
-                       Metric::new("some.timer", 12.1, MetricKind::Timer)];
+        let metrics = [Metric::new("some.timer", 13.1, MetricKind::Timer(1.0)),
+                       Metric::new("some.timer", 33.7, MetricKind::Timer(1.0)),
+                       Metric::new("some.timer", 3.4, MetricKind::Timer(1.0)),
+                       Metric::new("some.timer", 12.1, MetricKind::Timer(1.0))];
         for m in metrics.iter() {
-            buckets.add(&m);
+            buckets.add(m);
         }
         buckets
     }
@@ -91,17 +119,63 @@ mod test {
         let mut buckets = make_buckets();
         process(&mut buckets);
 
-        assert_eq!(Some(&3.4), buckets.timer_data().get("some.timer.min"));
-        assert_eq!(Some(&33.7), buckets.timer_data().get("some.timer.max"));
-        assert_eq!(Some(&4.0), buckets.timer_data().get("some.timer.count"));
+        assert_eq!(Some(&3.4), buckets.timer_data().get(&MetricKey::from("some.timer.min")));
+        assert_eq!(Some(&33.7), buckets.timer_data().get(&MetricKey::from("some.timer.max")));
+        assert_eq!(Some(&4.0), buckets.timer_data().get(&MetricKey::from("some.timer.count")));
         assert_float("15.575",
-                     buckets.timer_data().get("some.timer.mean").unwrap());
+                     buckets.timer_data().get(&MetricKey::from("some.timer.mean")).unwrap());
         assert_float("12.600",
-                     buckets.timer_data().get("some.timer.median").unwrap());
+                     buckets.timer_data().get(&MetricKey::from("some.timer.median")).unwrap());
         assert_float("11.124",
-                     buckets.timer_data().get("some.timer.stddev").unwrap());
+                     buckets.timer_data().get(&MetricKey::from("some.timer.stddev")).unwrap());
         assert_float("23.400",
-                     buckets.timer_data().get("some.timer.upper_95").unwrap());
+                     buckets.timer_data().get(&MetricKey::from("some.timer.upper_95")).unwrap());
+        assert_eq!(Some(&0.4), buckets.timer_data().get(&MetricKey::from("some.timer.count_ps")));
+    }
+
+    #[test]
+    fn test_process_sampled_timer_data() {
+        let mut buckets = Buckets::new();
+        buckets.set_flush_interval(5);
+        buckets.add(&Metric::new("some.timer", 10.0, MetricKind::Timer(0.1)));
+        buckets.add(&Metric::new("some.timer", 20.0, MetricKind::Timer(0.5)));
+        process(&mut buckets);
+
+        let data = buckets.timer_data();
+        assert_eq!(Some(&12.0), data.get(&MetricKey::from("some.timer.count")));
+        assert_eq!(Some(&2.4), data.get(&MetricKey::from("some.timer.count_ps")));
+        assert_eq!(Some(&10.0), data.get(&MetricKey::from("some.timer.min")));
+        assert_eq!(Some(&20.0), data.get(&MetricKey::from("some.timer.max")));
+    }
+
+    #[test]
+    fn test_process_histogram_and_distribution_data() {
+        let mut buckets = Buckets::new();
+        buckets.add(&Metric::new("some.size", 10.0, MetricKind::Histogram(1.0)));
+        buckets.add(&Metric::new("some.size", 20.0, MetricKind::Histogram(1.0)));
+        buckets.add(&Metric::new("some.size", 5.0, MetricKind::Distribution(0.5)));
+        process(&mut buckets);
+
+        assert_eq!(0, buckets.timer_data().len());
+        let data = buckets.histogram_data();
+        assert_eq!(Some(&2.0), data.get(&MetricKey::from("some.size.count")));
+        assert_eq!(Some(&15.0), data.get(&MetricKey::from("some.size.mean")));
+
+        let data = buckets.distribution_data();
+        assert_eq!(Some(&2.0), data.get(&MetricKey::from("some.size.count")));
+        assert_eq!(Some(&5.0), data.get(&MetricKey::from("some.size.max")));
+    }
+
+    #[test]
+    fn test_process_empty_timer() {
+        let mut buckets = make_buckets();
+        buckets.reset();
+        process(&mut buckets);
+
+        let data = buckets.timer_data();
+        assert_eq!(Some(&0.0), data.get(&MetricKey::from("some.timer.count")));
+        assert_eq!(Some(&0.0), data.get(&MetricKey::from("some.timer.count_ps")));
+        assert_eq!(None, data.get(&MetricKey::from("some.timer.min")));
     }
 
     #[test]
@@ -109,6 +183,6 @@ mod test {
         let mut buckets = make_buckets();
         process(&mut buckets);
 
-        assert_eq!(Some(&0.0), buckets.counters().get("statsd.processing_time"));
+        assert_eq!(Some(&0.0), buckets.counters().get(&MetricKey::from("statsd.processing_time")));
     }
 }
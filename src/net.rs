@@ -0,0 +1,257 @@
+//! Listening socket setup
+//!
+//! IPv6 sockets are created with an explicit `IPV6_V6ONLY` setting
+//! so dual-stack behaviour doesn't depend on OS defaults.
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+
+/// How long readers block before checking whether the server is stopping.
+pub const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How often non-blocking listeners check for new connections.
+pub const ACCEPT_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Whether a read failed because `READ_TIMEOUT` expired.
+///
+/// Unix reports `WouldBlock` and Windows reports `TimedOut`.
+pub fn timed_out(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+/// Whether an `accept()` error only affects the connection being accepted.
+pub fn connection_failed(err: &io::Error) -> bool {
+    matches!(err.kind(),
+             io::ErrorKind::ConnectionAborted |
+             io::ErrorKind::ConnectionReset |
+             io::ErrorKind::Interrupted)
+}
+
+/// Wait for a connection on a non-blocking listener.
+///
+/// Returns `None` once `stopping` is set, so listeners don't block
+/// in `accept()` after the server has stopped. Errors that only
+/// affect the connection being accepted are skipped.
+pub fn accept<S, F>(stopping: &AtomicBool, mut accept: F) -> io::Result<Option<S>>
+    where F: FnMut() -> io::Result<S>
+{
+    while !stopping.load(Ordering::Relaxed) {
+        match accept() {
+            Ok(stream) => return Ok(Some(stream)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+            Err(ref e) if connection_failed(e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+
+/// Limits how many connections are open at once.
+///
+/// Clones share the same count, so one limit can cover several listeners.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Count a new connection, or `None` if `max` are already open.
+    pub fn acquire(&self) -> Option<OpenConnection> {
+        if self.open.fetch_add(1, Ordering::Relaxed) >= self.max {
+            self.open.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(OpenConnection(self.open.clone()))
+    }
+}
+
+/// Counts a connection against its limit until it is dropped.
+pub struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+/// Bind a UDP socket for each of `workers` reader threads.
+///
+/// IPv6 sockets also accept IPv4 traffic unless `v6_only` is set.
+///
+/// On Unix each socket is bound to the same address with `SO_REUSEPORT`
+/// and the kernel spreads packets between them, elsewhere the sockets
+/// are clones that share one receive queue.
+pub fn bind_udp_workers(addr: SocketAddr,
+                        v6_only: bool,
+                        workers: usize)
+                        -> io::Result<Vec<UdpSocket>> {
+    let first = bind_udp_worker(addr, v6_only, workers)?;
+    // Later sockets need the port picked for the first one.
+    let addr = first.local_addr()?;
+    let mut sockets = Vec::with_capacity(workers);
+    for _ in 1..workers {
+        let socket = if reuse_port(workers) {
+            bind_udp_worker(addr, v6_only, workers)?
+        } else {
+            first.try_clone()?
+        };
+        sockets.push(socket);
+    }
+    sockets.insert(0, first);
+    Ok(sockets)
+}
+
+/// Bind a single socket for one of `workers` reader threads,
+/// used to replace a socket that failed.
+pub fn bind_udp_worker(addr: SocketAddr, v6_only: bool, workers: usize) -> io::Result<UdpSocket> {
+    udp_socket(addr, v6_only, reuse_port(workers))
+}
+
+fn reuse_port(workers: usize) -> bool {
+    cfg!(unix) && workers > 1
+}
+
+fn udp_socket(addr: SocketAddr, v6_only: bool, reuse_port: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Bind a TCP listener.
+///
+/// IPv6 listeners also accept IPv4 connections unless `v6_only` is set.
+pub fn bind_tcp(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // Matches std, which allows rebinding while old connections are in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    fn bind_udp(addr: SocketAddr, v6_only: bool) -> io::Result<UdpSocket> {
+        bind_udp_workers(addr, v6_only, 1).map(|mut sockets| sockets.remove(0))
+    }
+
+    fn has_ipv6() -> bool {
+        UdpSocket::bind("[::1]:0").is_ok()
+    }
+
+    #[test]
+    fn test_bind_udp_ipv4() {
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        assert!(socket.local_addr().unwrap().is_ipv4());
+    }
+
+    #[test]
+    fn test_bind_udp_dual_stack() {
+        if !has_ipv6() {
+            return;
+        }
+        let socket = bind_udp("[::]:0".parse().unwrap(), false).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"foo:1|c", SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(7, socket.recv(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_bind_udp_v6_only_with_ipv4() {
+        if !has_ipv6() {
+            return;
+        }
+        // The IPv4 and IPv6 wildcard addresses can share a port when v6 only.
+        let v4 = bind_udp("0.0.0.0:0".parse().unwrap(), false).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let v6 = bind_udp(SocketAddr::from(([0u16; 8], port)), true);
+        assert!(v6.is_ok());
+    }
+
+    #[test]
+    fn test_bind_udp_workers() {
+        let sockets = bind_udp_workers("127.0.0.1:0".parse().unwrap(), false, 4).unwrap();
+        assert_eq!(4, sockets.len());
+        let addr = sockets[0].local_addr().unwrap();
+        assert!(addr.port() > 0);
+        for socket in sockets.iter() {
+            assert_eq!(addr, socket.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_bind_udp_single_worker_exclusive() {
+        let sockets = bind_udp_workers("127.0.0.1:0".parse().unwrap(), false, 1).unwrap();
+        let addr = sockets[0].local_addr().unwrap();
+        assert!(bind_udp_workers(addr, false, 2).is_err());
+    }
+
+    #[test]
+    fn test_accept_stopping() {
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let stopping = AtomicBool::new(false);
+
+        let client = ::std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_, peer) = accept(&stopping, || listener.accept()).unwrap().unwrap();
+        assert_eq!(client.local_addr().unwrap(), peer);
+
+        stopping.store(true, Ordering::Relaxed);
+        assert!(accept(&stopping, || listener.accept()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(2);
+        let shared = limit.clone();
+        let first = limit.acquire().unwrap();
+        let _second = shared.acquire().unwrap();
+        assert!(limit.acquire().is_none());
+
+        drop(first);
+        assert!(shared.acquire().is_some());
+    }
+
+    #[test]
+    fn test_bind_tcp() {
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        assert!(listener.local_addr().unwrap().port() > 0);
+    }
+}
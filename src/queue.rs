@@ -0,0 +1,210 @@
+//! Bounded event queue
+//!
+//! Listeners publish events to the event loop through a queue with a
+//! fixed capacity, so a slow flush can't grow memory without limit.
+
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvError, SendError, SyncSender, TryRecvError,
+                      TrySendError};
+use std::sync::Arc;
+
+use server::Event;
+
+
+/// What listeners do with metrics when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    /// Discard UDP and datagram packets and count them as dropped.
+    /// Stream connections still wait, so their senders are slowed
+    /// down by flow control instead of losing lines.
+    DropNewest,
+    /// Wait for space, the socket buffers fill up instead.
+    Block,
+}
+
+/// Queue counters since the last flush.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueueStats {
+    /// Packets that were dropped because the queue was full.
+    pub dropped_packets: usize,
+    /// The most events that were waiting in the queue.
+    pub peak_depth: usize,
+}
+
+
+#[derive(Default)]
+struct Counters {
+    // Can be briefly negative when an event is received before
+    // the sender has counted it.
+    depth: AtomicIsize,
+    peak: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+
+/// Create a queue that holds up to `capacity` events.
+pub(crate) fn channel(capacity: usize, policy: QueuePolicy) -> (EventSender, EventReceiver) {
+    let (send, recv) = mpsc::sync_channel(capacity);
+    let counters = Arc::new(Counters::default());
+    let sender = EventSender {
+        chan: send,
+        policy,
+        counters: counters.clone(),
+    };
+    (sender, EventReceiver { chan: recv, counters })
+}
+
+
+/// Publishes events to the event loop.
+#[derive(Clone)]
+pub(crate) struct EventSender {
+    chan: SyncSender<Event>,
+    policy: QueuePolicy,
+    counters: Arc<Counters>,
+}
+
+impl EventSender {
+    /// Send an event to the event loop.
+    ///
+    /// Packets are dropped when the queue is full and the policy is
+    /// `DropNewest`, other events always wait for space. Returns an
+    /// error when the event loop has stopped.
+    pub fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        let packets = match event {
+            Event::Packets(ref packets) if self.policy == QueuePolicy::DropNewest => {
+                Some(packets.len())
+            }
+            _ => None,
+        };
+        if let Some(packets) = packets {
+            match self.chan.try_send(event) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    self.counters.dropped.fetch_add(packets, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(TrySendError::Disconnected(event)) => return Err(SendError(event)),
+            }
+        } else {
+            self.chan.send(event)?;
+        }
+        let depth = self.counters.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        if depth > 0 {
+            self.counters.peak.fetch_max(depth as usize, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Get the counters since the event loop last reset them.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            dropped_packets: self.counters.dropped.load(Ordering::Relaxed),
+            peak_depth: self.counters.peak.load(Ordering::Relaxed),
+        }
+    }
+}
+
+
+/// Receives events on the event loop.
+pub(crate) struct EventReceiver {
+    chan: Receiver<Event>,
+    counters: Arc<Counters>,
+}
+
+impl EventReceiver {
+    /// Wait for the next event.
+    pub fn recv(&self) -> Result<Event, RecvError> {
+        let event = self.chan.recv()?;
+        self.counters.depth.fetch_sub(1, Ordering::Relaxed);
+        Ok(event)
+    }
+
+    /// Get the next event if there is one waiting.
+    pub fn try_recv(&self) -> Result<Event, TryRecvError> {
+        let event = self.chan.try_recv()?;
+        self.counters.depth.fetch_sub(1, Ordering::Relaxed);
+        Ok(event)
+    }
+
+    /// Get the events that are waiting without blocking.
+    pub fn try_iter<'a>(&'a self) -> impl Iterator<Item = Event> + 'a {
+        ::std::iter::from_fn(move || self.try_recv().ok())
+    }
+
+    /// Get the counters and start counting again.
+    ///
+    /// The peak depth starts from the events that are still waiting.
+    pub fn reset_stats(&self) -> QueueStats {
+        let depth = self.counters.depth.load(Ordering::Relaxed).max(0) as usize;
+        QueueStats {
+            dropped_packets: self.counters.dropped.swap(0, Ordering::Relaxed),
+            peak_depth: self.counters.peak.swap(depth, Ordering::Relaxed),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use events::ParsedPacket;
+
+    fn packets(count: usize) -> Event {
+        Event::Packets((0..count).map(|_| ParsedPacket::parse(b"foo:1|c", false)).collect())
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let (send, recv) = channel(2, QueuePolicy::DropNewest);
+        send.send(packets(1)).unwrap();
+        send.send(packets(1)).unwrap();
+        send.send(packets(3)).unwrap();
+        assert_eq!(QueueStats { dropped_packets: 3, peak_depth: 2 }, send.stats());
+        assert_eq!(2, recv.try_iter().count());
+    }
+
+    #[test]
+    fn test_control_events_wait() {
+        let (send, recv) = channel(1, QueuePolicy::DropNewest);
+        send.send(packets(1)).unwrap();
+        let sender = ::std::thread::spawn(move || send.send(Event::Shutdown).is_ok());
+        assert!(recv.recv().is_ok());
+        assert!(matches!(recv.recv(), Ok(Event::Shutdown)));
+        assert!(sender.join().unwrap());
+        assert_eq!(0, recv.reset_stats().dropped_packets);
+    }
+
+    #[test]
+    fn test_stream_messages_wait() {
+        let (send, recv) = channel(1, QueuePolicy::DropNewest);
+        send.send(packets(1)).unwrap();
+        let sender = ::std::thread::spawn(move || {
+            send.send(Event::StreamMessage(1, b"foo:1|c\n".to_vec())).is_ok()
+        });
+        assert!(recv.recv().is_ok());
+        assert!(matches!(recv.recv(), Ok(Event::StreamMessage(1, _))));
+        assert!(sender.join().unwrap());
+        assert_eq!(0, recv.reset_stats().dropped_packets);
+    }
+
+    #[test]
+    fn test_reset_stats() {
+        let (send, recv) = channel(4, QueuePolicy::Block);
+        send.send(packets(1)).unwrap();
+        send.send(packets(1)).unwrap();
+        send.send(packets(1)).unwrap();
+        recv.try_recv().unwrap();
+        recv.try_recv().unwrap();
+
+        assert_eq!(QueueStats { dropped_packets: 0, peak_depth: 3 }, recv.reset_stats());
+        assert_eq!(1, send.stats().peak_depth);
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let (send, recv) = channel(1, QueuePolicy::DropNewest);
+        drop(recv);
+        assert!(send.send(packets(1)).is_err());
+        assert!(send.send(Event::Shutdown).is_err());
+    }
+}
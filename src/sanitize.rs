@@ -0,0 +1,188 @@
+//! Metric name sanitization
+//!
+//! Cleans up metric names before they are aggregated so that they
+//! can be safely sent to backends like graphite's plaintext protocol.
+//! The rules match the `keyNameSanitize` behavior of etsy/statsd.
+
+use super::metric::Metric;
+
+
+/// The result of sanitizing a metric.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// The metric did not need changes.
+    Unchanged,
+    /// The metric name or tags were changed.
+    Renamed,
+    /// The metric name is empty after sanitization or too long.
+    Rejected,
+}
+
+
+/// Sanitization configuration.
+#[derive(Debug, Clone)]
+pub struct Sanitizer {
+    enabled: bool,
+    max_length: usize,
+}
+
+impl Sanitizer {
+    /// Create a sanitizer.
+    ///
+    /// A `max_length` of 0 allows names of any length.
+    pub fn new(enabled: bool, max_length: usize) -> Sanitizer {
+        Sanitizer {
+            enabled,
+            max_length,
+        }
+    }
+
+    /// Sanitize a metric's name and tags in place.
+    ///
+    /// - Runs of whitespace become `_`.
+    /// - `/` becomes `-`.
+    /// - Characters other than `a-z`, `A-Z`, `0-9`, `_`, `-` and `.` are removed.
+    /// - Tags with a key that is empty after cleaning are dropped.
+    pub fn sanitize(&self, metric: &mut Metric) -> Outcome {
+        let mut outcome = Outcome::Unchanged;
+        if self.enabled {
+            if let Some(name) = clean(&metric.name) {
+                metric.name = name;
+                outcome = Outcome::Renamed;
+            }
+            for tag in metric.tags.iter_mut() {
+                if let Some(key) = clean(&tag.0) {
+                    tag.0 = key;
+                    outcome = Outcome::Renamed;
+                }
+                if let Some(value) = clean(&tag.1) {
+                    tag.1 = value;
+                    outcome = Outcome::Renamed;
+                }
+            }
+            // Tags without a key can't be sent to backends.
+            let tags = metric.tags.len();
+            metric.tags.retain(|tag| !tag.0.is_empty());
+            if metric.tags.len() != tags {
+                outcome = Outcome::Renamed;
+            }
+        }
+        if metric.name.is_empty() || (self.max_length > 0 && metric.name.len() > self.max_length) {
+            return Outcome::Rejected;
+        }
+        outcome
+    }
+}
+
+impl Default for Sanitizer {
+    fn default() -> Sanitizer {
+        Sanitizer::new(true, 0)
+    }
+}
+
+
+/// Sanitize a single name, e.g. an event title that is used in a path.
+pub fn clean_name(name: &str) -> String {
+    clean(name).unwrap_or_else(|| name.to_string())
+}
+
+
+/// Clean a single name, returning `None` when no changes were needed.
+fn clean(name: &str) -> Option<String> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.';
+    if name.chars().all(allowed) {
+        return None;
+    }
+
+    let mut out = String::with_capacity(name.len());
+    let mut in_space = false;
+    for c in name.chars() {
+        if c.is_whitespace() {
+            if !in_space {
+                out.push('_');
+            }
+            in_space = true;
+            continue;
+        }
+        in_space = false;
+        if c == '/' {
+            out.push('-');
+        } else if allowed(c) {
+            out.push(c);
+        }
+    }
+    Some(out)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::metric::{Metric, MetricKind};
+
+    fn metric(name: &str) -> Metric {
+        Metric::new(name, 1.0, MetricKind::Counter(1.0))
+    }
+
+    #[test]
+    fn test_sanitize_unchanged() {
+        let sanitizer = Sanitizer::default();
+        let mut m = metric("some.metric-name_1");
+        assert_eq!(Outcome::Unchanged, sanitizer.sanitize(&mut m));
+        assert_eq!("some.metric-name_1", m.name);
+    }
+
+    #[test]
+    fn test_sanitize_renamed() {
+        let sanitizer = Sanitizer::default();
+        let cases = vec![("some metric", "some_metric"),
+                         ("some  \t metric", "some_metric"),
+                         ("some/metric", "some-metric"),
+                         ("some.métric!", "some.mtric"),
+                         ("a;b=c", "abc")];
+        for (input, expected) in cases {
+            let mut m = metric(input);
+            assert_eq!(Outcome::Renamed, sanitizer.sanitize(&mut m));
+            assert_eq!(expected, m.name);
+        }
+    }
+
+    #[test]
+    fn test_sanitize_tags() {
+        let sanitizer = Sanitizer::default();
+        let mut m = metric("foo").with_tags(vec![("env name".to_string(), "a/b".to_string())]);
+        assert_eq!(Outcome::Renamed, sanitizer.sanitize(&mut m));
+        assert_eq!(vec![("env_name".to_string(), "a-b".to_string())], m.tags);
+    }
+
+    #[test]
+    fn test_sanitize_empty_tag_key() {
+        let sanitizer = Sanitizer::default();
+        let mut m = metric("foo").with_tags(vec![("!!".to_string(), "a".to_string()),
+                                                 ("env".to_string(), "prod".to_string())]);
+        assert_eq!(Outcome::Renamed, sanitizer.sanitize(&mut m));
+        assert_eq!(vec![("env".to_string(), "prod".to_string())], m.tags);
+    }
+
+    #[test]
+    fn test_sanitize_rejected() {
+        let sanitizer = Sanitizer::new(true, 5);
+        assert_eq!(Outcome::Rejected, sanitizer.sanitize(&mut metric("!!!")));
+        assert_eq!(Outcome::Rejected, sanitizer.sanitize(&mut metric("too.long")));
+        assert_eq!(Outcome::Unchanged, sanitizer.sanitize(&mut metric("short")));
+    }
+
+    #[test]
+    fn test_clean_name() {
+        assert_eq!("Deploy_v1.2", clean_name("Deploy v1.2"));
+        assert_eq!("ok", clean_name("ok"));
+    }
+
+    #[test]
+    fn test_sanitize_disabled() {
+        let sanitizer = Sanitizer::new(false, 0);
+        let mut m = metric("some metric");
+        assert_eq!(Outcome::Unchanged, sanitizer.sanitize(&mut m));
+        assert_eq!("some metric", m.name);
+    }
+}
@@ -1,55 +1,1645 @@
-use std::sync::mpsc::Sender;
-use std::net::{Ipv4Addr, TcpStream, TcpListener, UdpSocket, SocketAddrV4};
-use std::thread::sleep;
-use std::time::Duration;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use backend::Backend;
+use buckets::Buckets;
+use events::ParsedPacket;
+use management;
+use net::{self, ConnectionLimit};
+use queue::{self, EventReceiver, EventSender, QueuePolicy};
+use sanitize::Sanitizer;
+use stream;
+use supervisor::{self, BACKOFF};
+#[cfg(unix)]
+use unix;
+use udp::{BatchReceiver, MAX_BUFFER_SIZE};
 
 
 /// Acceptable event types.
 ///
-pub enum Event {
-    UdpMessage(Vec<u8>),
-    TcpMessage(TcpStream),
-    TimerFlush,
+pub(crate) enum Event {
+    // Packets parsed on the thread that received them.
+    Packets(Vec<ParsedPacket>),
+    // Stream connections are identified by a connection id.
+    StreamConnected(usize, String),
+    StreamMessage(usize, Vec<u8>),
+    StreamLineTooLong(usize),
+    StreamClosed(usize),
+    // A stream connection closed because too many were open.
+    StreamRejected,
+    // Listener health, listeners are identified by name.
+    ListenerStarted(String),
+    ListenerError(String, String),
+    ListenerRestarted(String),
+    ListenerFailed(String, String),
+    // The wall clock time of the flush and when it was due.
+    TimerFlush(i64, Instant),
+    // A new configuration, or why it couldn't be loaded.
+    Reload(Result<Reload, String>),
+    Shutdown,
 }
 
 
-/// Setup the UDP socket that listens for metrics and
-/// publishes them into the bucket storage.
-pub fn udp_server(chan: Sender<Event>, port: u16) {
-    let addr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
-    let socket = UdpSocket::bind(addr).ok().unwrap();
-    let mut buf = [0; 256];
-    loop {
-        let (len, _) = match socket.recv_from(&mut buf) {
-            Ok(r) => r,
-            Err(_) => panic!("Could not read UDP socket."),
+/// Builds and starts a statsd server.
+///
+/// ```no_run
+/// use statsd::backends::console::Console;
+/// use statsd::server::Server;
+///
+/// let server = Server::new()
+///     .udp_addr("127.0.0.1:8125".parse().unwrap())
+///     .flush_interval(10)
+///     .backend(Box::new(Console::new()))
+///     .start()
+///     .unwrap();
+/// server.join().unwrap();
+/// ```
+pub struct Server {
+    udp_addrs: Vec<SocketAddr>,
+    tcp_addrs: Vec<SocketAddr>,
+    admin_addr: SocketAddr,
+    admin_max_sessions: usize,
+    admin_idle_timeout: u64,
+    max_connections: usize,
+    flush_interval: u64,
+    udp_buffer_size: usize,
+    udp_workers: usize,
+    queue_capacity: usize,
+    queue_policy: QueuePolicy,
+    #[cfg(unix)]
+    unix_datagram_path: Option<PathBuf>,
+    #[cfg(unix)]
+    unix_stream_path: Option<PathBuf>,
+    #[cfg(unix)]
+    unix_socket_mode: u32,
+    backends: Vec<Box<dyn Backend>>,
+    buckets: Buckets,
+}
+
+impl Server {
+    /// Create a server with the default settings.
+    ///
+    /// Listens for metrics on 0.0.0.0:8125 and admin connections on
+    /// 127.0.0.1:8126, flushing every 10 seconds to no backends.
+    pub fn new() -> Server {
+        Server {
+            udp_addrs: Vec::new(),
+            tcp_addrs: Vec::new(),
+            admin_addr: "127.0.0.1:8126".parse().unwrap(),
+            admin_max_sessions: 16,
+            admin_idle_timeout: 300,
+            max_connections: 1024,
+            flush_interval: 10,
+            udp_buffer_size: MAX_BUFFER_SIZE,
+            udp_workers: 1,
+            queue_capacity: 10000,
+            queue_policy: QueuePolicy::DropNewest,
+            #[cfg(unix)]
+            unix_datagram_path: None,
+            #[cfg(unix)]
+            unix_stream_path: None,
+            #[cfg(unix)]
+            unix_socket_mode: 0o660,
+            backends: Vec::new(),
+            buckets: Buckets::new(),
+        }
+    }
+
+    /// Add an address to receive metrics on, replacing the default.
+    ///
+    /// Can be called more than once to listen on several addresses.
+    /// Use port 0 to have the OS pick a port, see `ServerHandle::udp_addrs()`.
+    ///
+    /// The IPv6 wildcard address `::` also accepts IPv4 packets, unless
+    /// an IPv4 address is added as well.
+    pub fn udp_addr(mut self, addr: SocketAddr) -> Server {
+        self.udp_addrs.push(addr);
+        self
+    }
+
+    /// Add an address to accept newline delimited metrics on over TCP.
+    ///
+    /// TCP ingestion is disabled by default. IPv6 addresses are handled
+    /// the same way as in `udp_addr()`.
+    pub fn tcp_addr(mut self, addr: SocketAddr) -> Server {
+        self.tcp_addrs.push(addr);
+        self
+    }
+
+    /// Accept metric packets on a Unix datagram socket at this path.
+    #[cfg(unix)]
+    pub fn unix_datagram_path<P: Into<PathBuf>>(mut self, path: P) -> Server {
+        self.unix_datagram_path = Some(path.into());
+        self
+    }
+
+    /// Accept newline delimited metrics on a Unix stream socket at this path.
+    #[cfg(unix)]
+    pub fn unix_stream_path<P: Into<PathBuf>>(mut self, path: P) -> Server {
+        self.unix_stream_path = Some(path.into());
+        self
+    }
+
+    /// Set the file mode of the Unix sockets, 0o660 by default.
+    #[cfg(unix)]
+    pub fn unix_socket_mode(mut self, mode: u32) -> Server {
+        self.unix_socket_mode = mode;
+        self
+    }
+
+    /// Set how many TCP and Unix stream connections can be open at once,
+    /// 1024 by default.
+    ///
+    /// Further connections are closed and counted in
+    /// `Buckets::rejected_connections()`. At least one must be allowed.
+    pub fn max_connections(mut self, connections: usize) -> Server {
+        self.max_connections = connections;
+        self
+    }
+
+    /// Set the address the admin server listens on.
+    pub fn admin_addr(mut self, addr: SocketAddr) -> Server {
+        self.admin_addr = addr;
+        self
+    }
+
+    /// Set how many admin connections can be open at once, 16 by default.
+    ///
+    /// Further connections are sent an error and closed.
+    /// At least one session must be allowed.
+    pub fn admin_max_sessions(mut self, sessions: usize) -> Server {
+        self.admin_max_sessions = sessions;
+        self
+    }
+
+    /// Set how many seconds an admin connection can wait
+    /// between commands before it is closed, 300 by default.
+    pub fn admin_idle_timeout(mut self, timeout: u64) -> Server {
+        self.admin_idle_timeout = timeout;
+        self
+    }
+
+    /// Set the flush interval in seconds.
+    pub fn flush_interval(mut self, interval: u64) -> Server {
+        self.flush_interval = interval;
+        self.buckets.set_flush_interval(interval);
+        self
+    }
+
+    /// Set the largest UDP packet in bytes that can be received, up to 64 KiB.
+    ///
+    /// Larger packets are truncated, complete lines in them are still
+    /// added and the packet is counted in `Buckets::truncated_packets()`.
+    pub fn udp_buffer_size(mut self, size: usize) -> Server {
+        self.udp_buffer_size = size;
+        self
+    }
+
+    /// Set the number of threads that read and parse UDP packets
+    /// for each address, 1 by default.
+    ///
+    /// On Unix each thread has its own socket bound with `SO_REUSEPORT`.
+    /// The kernel picks a socket by hashing the sender's address, so
+    /// packets from a single client are always read by the same thread.
+    pub fn udp_workers(mut self, workers: usize) -> Server {
+        self.udp_workers = workers;
+        self
+    }
+
+    /// Set how many events can wait for the event loop, 10000 by default.
+    ///
+    /// Each UDP read is one event and can hold several packets.
+    pub fn queue_capacity(mut self, capacity: usize) -> Server {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// Set what happens to metrics when the event queue is full.
+    ///
+    /// UDP and datagram packets are dropped and counted in
+    /// `Buckets::dropped_packets()` by default. Stream connections
+    /// always wait for space.
+    pub fn queue_policy(mut self, policy: QueuePolicy) -> Server {
+        self.queue_policy = policy;
+        self
+    }
+
+    /// Set how far from the current time client supplied timestamps can be.
+    pub fn timestamp_window(mut self, window: u64) -> Server {
+        self.buckets.set_timestamp_window(window);
+        self
+    }
+
+    /// Set how metric names are sanitized.
+    pub fn sanitizer(mut self, sanitizer: Sanitizer) -> Server {
+        self.buckets.set_sanitizer(sanitizer);
+        self
+    }
+
+    /// Add a backend that metrics are flushed to.
+    pub fn backend(mut self, backend: Box<dyn Backend>) -> Server {
+        self.backends.push(backend);
+        self
+    }
+
+    /// Bind the listening sockets and start the server threads.
+    ///
+    /// Returns an error if any socket can't be bound, or a setting
+    /// such as the flush interval is invalid. Socket files left behind by a
+    /// server that is no longer running are replaced.
+    pub fn start(mut self) -> io::Result<ServerHandle> {
+        if self.udp_buffer_size == 0 || self.udp_buffer_size > MAX_BUFFER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("UDP buffer size must be between 1 and {}",
+                                              MAX_BUFFER_SIZE)));
+        }
+        if self.flush_interval == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "The flush interval must be at least 1 second"));
+        }
+        if self.udp_workers == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "There must be at least one UDP worker"));
+        }
+        if self.queue_capacity == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "The queue capacity must be at least 1"));
+        }
+        if self.max_connections == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "At least 1 stream connection must be allowed"));
+        }
+        if self.admin_max_sessions == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "The admin server must allow at least 1 session"));
+        }
+        if self.admin_idle_timeout == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "The admin idle timeout must be at least 1 second"));
+        }
+        if self.udp_addrs.is_empty() {
+            self.udp_addrs.push(SocketAddr::from(([0, 0, 0, 0], 8125)));
+        }
+        let udp_v6_only = has_ipv4(&self.udp_addrs);
+        let mut sockets = Vec::new();
+        let mut udp_addrs = Vec::new();
+        for addr in self.udp_addrs.iter() {
+            let workers = net::bind_udp_workers(*addr, udp_v6_only, self.udp_workers)?;
+            let addr = workers[0].local_addr()?;
+            udp_addrs.push(addr);
+            sockets.extend(workers.into_iter().enumerate().map(|(i, socket)| (addr, i, socket)));
+        }
+        let tcp_v6_only = has_ipv4(&self.tcp_addrs);
+        let mut tcp_listeners = Vec::new();
+        let mut tcp_addrs = Vec::new();
+        for addr in self.tcp_addrs.iter() {
+            let listener = net::bind_tcp(*addr, tcp_v6_only)?;
+            tcp_addrs.push(listener.local_addr()?);
+            tcp_listeners.push(listener);
+        }
+        let listener = TcpListener::bind(self.admin_addr)?;
+        let admin_addr = listener.local_addr()?;
+        #[cfg(unix)]
+        let unix_datagram = match self.unix_datagram_path {
+            Some(ref path) => Some(unix::bind_datagram(path, self.unix_socket_mode)?),
+            None => None,
+        };
+        #[cfg(unix)]
+        let unix_stream = match self.unix_stream_path {
+            Some(ref path) => Some(unix::bind_stream(path, self.unix_socket_mode)?),
+            None => None,
+        };
+        #[cfg(unix)]
+        let socket_paths: Vec<PathBuf> = self.unix_datagram_path
+                                             .iter()
+                                             .chain(self.unix_stream_path.iter())
+                                             .cloned()
+                                             .collect();
+        #[cfg(not(unix))]
+        let socket_paths = Vec::new();
+
+        let (event_send, event_recv) = queue::channel(self.queue_capacity, self.queue_policy);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let mut listeners = Vec::new();
+        let buffer_size = self.udp_buffer_size;
+        let workers = self.udp_workers;
+        for (addr, worker, socket) in sockets {
+            let name = if workers > 1 {
+                format!("udp {} worker {}", addr, worker + 1)
+            } else {
+                format!("udp {}", addr)
+            };
+            let udp_send = event_send.clone();
+            let udp_stopping = stopping.clone();
+            listeners.push(spawn_listener(&event_send,
+                                          &stopping,
+                                          name,
+                                          socket,
+                                          move || {
+                                              net::bind_udp_worker(addr, udp_v6_only, workers)
+                                          },
+                                          move |socket| {
+                                              udp_server(udp_send.clone(),
+                                                         socket,
+                                                         buffer_size,
+                                                         &udp_stopping)
+                                          }));
+        }
+        let connection_limit = ConnectionLimit::new(self.max_connections);
+        for tcp_listener in tcp_listeners {
+            let addr = tcp_listener.local_addr()?;
+            let stream_send = event_send.clone();
+            let tcp_limit = connection_limit.clone();
+            let tcp_stopping = stopping.clone();
+            listeners.push(spawn_listener(&event_send,
+                                          &stopping,
+                                          format!("tcp {}", addr),
+                                          tcp_listener,
+                                          move || net::bind_tcp(addr, tcp_v6_only),
+                                          move |listener| {
+                                              tcp_server(stream_send.clone(),
+                                                         listener,
+                                                         &tcp_limit,
+                                                         tcp_stopping.clone())
+                                          }));
+        }
+        #[cfg(unix)]
+        {
+            let mode = self.unix_socket_mode;
+            if let Some(socket) = unix_datagram {
+                let path = self.unix_datagram_path.clone().unwrap();
+                let bind_path = path.clone();
+                let unix_send = event_send.clone();
+                let unix_stopping = stopping.clone();
+                listeners.push(spawn_listener(&event_send,
+                                              &stopping,
+                                              format!("unix {}", path.display()),
+                                              socket,
+                                              move || unix::bind_datagram(&bind_path, mode),
+                                              move |socket| {
+                                                  unix::datagram_server(unix_send.clone(),
+                                                                        socket,
+                                                                        buffer_size,
+                                                                        &unix_stopping)
+                                              }));
+            }
+            if let Some(listener) = unix_stream {
+                let path = self.unix_stream_path.clone().unwrap();
+                let bind_path = path.clone();
+                let unix_send = event_send.clone();
+                let unix_limit = connection_limit.clone();
+                let unix_stopping = stopping.clone();
+                listeners.push(spawn_listener(&event_send,
+                                              &stopping,
+                                              format!("unix {}", path.display()),
+                                              listener,
+                                              move || unix::bind_stream(&bind_path, mode),
+                                              move |listener| {
+                                                  unix::stream_server(unix_send.clone(),
+                                                                      listener,
+                                                                      &path,
+                                                                      &unix_limit,
+                                                                      unix_stopping.clone())
+                                              }));
+            }
+        }
+        let buckets = Arc::new(Mutex::new(self.buckets));
+        let admin_send = event_send.clone();
+        let admin_buckets = buckets.clone();
+        let max_sessions = self.admin_max_sessions;
+        let idle_timeout = Duration::from_secs(self.admin_idle_timeout);
+        let admin_stopping = stopping.clone();
+        listeners.push(spawn_listener(&event_send,
+                                      &stopping,
+                                      format!("admin {}", admin_addr),
+                                      listener,
+                                      move || TcpListener::bind(admin_addr),
+                                      move |listener| {
+                                          admin_server(admin_send.clone(),
+                                                       admin_buckets.clone(),
+                                                       listener,
+                                                       max_sessions,
+                                                       idle_timeout,
+                                                       &admin_stopping)
+                                      }));
+        let flush_send = event_send.clone();
+        let interval = self.flush_interval;
+        let (interval_send, interval_recv) = channel();
+        thread::spawn(move || {
+            flush_timer_loop(flush_send, interval, interval_recv);
+        });
+
+        let mut backends = self.backends;
+        let (done_send, done) = channel();
+        let loop_stopping = stopping.clone();
+        let thread = thread::spawn(move || {
+            let result = event_loop(&event_recv, &buckets, &mut backends, interval_send);
+            // Stop listeners that are still running after a failure.
+            loop_stopping.store(true, Ordering::Relaxed);
+            finish(&event_recv, &buckets, &mut backends, listeners);
+            let _ = done_send.send(());
+            result
+        });
+
+        Ok(ServerHandle {
+            udp_addrs,
+            tcp_addrs,
+            admin_addr,
+            socket_paths,
+            shutdown: Shutdown {
+                chan: event_send,
+                stopping,
+            },
+            done,
+            thread,
+        })
+    }
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+
+/// Settings that can be changed while the server is running.
+///
+/// Metrics received before a reload are kept and sent
+/// to the new backends on the next flush.
+pub struct Reload {
+    flush_interval: u64,
+    timestamp_window: u64,
+    sanitizer: Sanitizer,
+    backends: Vec<Box<dyn Backend>>,
+}
+
+impl Reload {
+    /// Create settings with the same defaults as `Server::new()`.
+    pub fn new() -> Reload {
+        Reload {
+            flush_interval: 10,
+            timestamp_window: 3600,
+            sanitizer: Sanitizer::default(),
+            backends: Vec::new(),
+        }
+    }
+
+    /// Set the flush interval in seconds.
+    ///
+    /// A reload with an interval of 0 fails and is
+    /// counted in `Buckets::failed_reloads()`.
+    pub fn flush_interval(mut self, interval: u64) -> Reload {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Set how far from the current time client supplied timestamps can be.
+    pub fn timestamp_window(mut self, window: u64) -> Reload {
+        self.timestamp_window = window;
+        self
+    }
+
+    /// Set how metric names are sanitized.
+    pub fn sanitizer(mut self, sanitizer: Sanitizer) -> Reload {
+        self.sanitizer = sanitizer;
+        self
+    }
+
+    /// Add a backend, replacing the backends the server was using.
+    pub fn backend(mut self, backend: Box<dyn Backend>) -> Reload {
+        self.backends.push(backend);
+        self
+    }
+}
+
+impl Default for Reload {
+    fn default() -> Reload {
+        Reload::new()
+    }
+}
+
+
+/// A running server.
+pub struct ServerHandle {
+    udp_addrs: Vec<SocketAddr>,
+    tcp_addrs: Vec<SocketAddr>,
+    admin_addr: SocketAddr,
+    // Unix socket files that are removed when the server stops.
+    socket_paths: Vec<PathBuf>,
+    shutdown: Shutdown,
+    // Closed when the event loop has finished.
+    done: Receiver<()>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    /// Get the first address metrics are received on.
+    pub fn udp_addr(&self) -> SocketAddr {
+        self.udp_addrs[0]
+    }
+
+    /// Get all the addresses metrics are received on.
+    pub fn udp_addrs(&self) -> &[SocketAddr] {
+        &self.udp_addrs
+    }
+
+    /// Get the first address metrics are received on over TCP, if enabled.
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        self.tcp_addrs.first().cloned()
+    }
+
+    /// Get all the addresses metrics are received on over TCP.
+    pub fn tcp_addrs(&self) -> &[SocketAddr] {
+        &self.tcp_addrs
+    }
+
+    /// Get the address of the admin server.
+    pub fn admin_addr(&self) -> SocketAddr {
+        self.admin_addr
+    }
+
+    /// Get a handle that can stop the server from another thread.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Get a handle that can reload the server from another thread.
+    pub fn reload_handle(&self) -> Reloader {
+        Reloader { chan: self.shutdown.chan.clone() }
+    }
+
+    /// Stop the server and wait for it to finish.
+    pub fn shutdown(self) {
+        self.shutdown.shutdown();
+        let _ = self.join();
+    }
+
+    /// Wait for the server to stop.
+    ///
+    /// Returns an error if the server stopped because
+    /// a listener failed and couldn't be bound again.
+    pub fn join(self) -> io::Result<()> {
+        let result = match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::other("The server thread panicked")),
         };
-        let bytes = Vec::from(&buf[..len]);
-        chan.send(Event::UdpMessage(bytes)).unwrap();
+        remove_socket_files(&self.socket_paths);
+        result
+    }
+
+    /// Wait up to `timeout` for the server to stop.
+    ///
+    /// Returns `None` if the server is still running, for example
+    /// when a backend is stuck in its final flush.
+    pub fn join_timeout(self, timeout: Duration) -> Option<io::Result<()>> {
+        match self.done.recv_timeout(timeout) {
+            Ok(_) | Err(RecvTimeoutError::Disconnected) => Some(self.join()),
+            Err(RecvTimeoutError::Timeout) => {
+                remove_socket_files(&self.socket_paths);
+                None
+            }
+        }
+    }
+}
+
+fn remove_socket_files(paths: &[PathBuf]) {
+    for path in paths.iter() {
+        let _ = fs::remove_file(path);
+    }
+}
+
+
+/// Stops a running server.
+#[derive(Clone)]
+pub struct Shutdown {
+    chan: EventSender,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// Ask the server to stop.
+    ///
+    /// The listeners stop reading, then metrics that were already
+    /// received are flushed to every backend before the server stops.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        let _ = self.chan.send(Event::Shutdown);
+    }
+}
+
+
+/// Changes the settings of a running server.
+#[derive(Clone)]
+pub struct Reloader {
+    chan: EventSender,
+}
+
+impl Reloader {
+    /// Replace the backends and settings of the running server.
+    pub fn reload(&self, reload: Reload) {
+        let _ = self.chan.send(Event::Reload(Ok(reload)));
     }
+
+    /// Record that new settings couldn't be loaded.
+    ///
+    /// The server keeps its current settings and the error
+    /// is shown by the admin `stats` command.
+    pub fn reload_failed(&self, error: String) {
+        let _ = self.chan.send(Event::Reload(Err(error)));
+    }
+}
+
+
+/// IPv6 wildcard sockets can't accept IPv4 traffic when
+/// an IPv4 address is also bound to the same port.
+fn has_ipv4(addrs: &[SocketAddr]) -> bool {
+    addrs.iter().any(|addr| addr.is_ipv4())
+}
+
+
+/// Lock the buckets shared with the admin sessions.
+///
+/// The buckets stay usable after a thread panicked holding the lock.
+pub(crate) fn lock_buckets(buckets: &Mutex<Buckets>) -> MutexGuard<'_, Buckets> {
+    buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Receive events until the server is shut down,
+/// or stops because a listener failed.
+///
+/// The buckets are locked for each event, so admin sessions
+/// can read them in between.
+fn event_loop(events: &EventReceiver,
+              shared: &Mutex<Buckets>,
+              backends: &mut Vec<Box<dyn Backend>>,
+              flush_interval: Sender<u64>)
+              -> io::Result<()> {
+    while let Ok(event) = events.recv() {
+        let mut buckets = lock_buckets(shared);
+        match event {
+            Event::TimerFlush(timestamp, due) => {
+                buckets.set_flush_time(timestamp, Instant::now().saturating_duration_since(due));
+                drop(buckets);
+                flush(shared, backends, events);
+            }
+
+            Event::Reload(Ok(ref reload)) if reload.flush_interval == 0 => {
+                buckets.add_reload(Some("The flush interval must be at least 1 second"
+                                            .to_string()));
+            }
+
+            Event::Reload(Ok(reload)) => {
+                buckets.set_flush_interval(reload.flush_interval);
+                buckets.set_timestamp_window(reload.timestamp_window);
+                buckets.set_sanitizer(reload.sanitizer);
+                *backends = reload.backends;
+                let _ = flush_interval.send(reload.flush_interval);
+                buckets.add_reload(None);
+            }
+
+            Event::Reload(Err(error)) => buckets.add_reload(Some(error)),
+
+            Event::ListenerStarted(name) => buckets.add_listener(name),
+            Event::ListenerError(name, error) => buckets.add_listener_error(&name, error),
+            Event::ListenerRestarted(name) => buckets.add_listener_restart(&name),
+            Event::ListenerFailed(name, error) => {
+                buckets.add_listener_failure(&name, error.clone());
+                return Err(io::Error::other(format!("The {} listener failed: {}", name, error)));
+            }
+
+            Event::Shutdown => return Ok(()),
+
+            event => add_event(event, &mut buckets),
+        }
+    }
+    Ok(())
 }
 
-/// Setup the TCP socket that listens for management commands.
-pub fn admin_server(chan: Sender<Event>, port: u16, host: &str) {
-    let tcp = TcpListener::bind((host, port)).unwrap();
-    for stream in tcp.incoming() {
-        match stream {
-            Ok(stream) => {
-                chan.send(Event::TcpMessage(stream)).unwrap();
+/// Wait for the listeners to stop, then flush everything received.
+///
+/// The queue is drained while waiting, so listeners blocked
+/// on a full queue can finish. The final flush is stamped with
+/// the flush boundary that was pending, like a timed flush.
+fn finish(events: &EventReceiver,
+          buckets: &Mutex<Buckets>,
+          backends: &mut [Box<dyn Backend>],
+          listeners: Vec<JoinHandle<()>>) {
+    while listeners.iter().any(|listener| !listener.is_finished()) {
+        drain(events, buckets);
+        thread::sleep(net::ACCEPT_INTERVAL);
+    }
+    for listener in listeners {
+        let _ = listener.join();
+    }
+    drain(events, buckets);
+    {
+        let mut buckets = lock_buckets(buckets);
+        let (timestamp, _) = next_flush(buckets.flush_interval(),
+                                        SystemTime::now(),
+                                        Instant::now());
+        buckets.set_flush_time(timestamp, Duration::new(0, 0));
+    }
+    flush(buckets, backends, events);
+}
+
+/// Add the metrics listeners queued before they stopped.
+fn drain(events: &EventReceiver, buckets: &Mutex<Buckets>) {
+    for event in events.try_iter() {
+        add_event(event, &mut lock_buckets(buckets));
+    }
+}
+
+/// Add received metrics to the buckets.
+///
+/// Events that don't carry metrics are ignored.
+fn add_event(event: Event, buckets: &mut Buckets) {
+    match event {
+        Event::Packets(packets) => {
+            for packet in packets {
+                buckets.add_parsed(packet);
             }
-            Err(e) => panic!("Unable to establish TCP socket: {}", e),
         }
+
+        Event::StreamConnected(id, peer) => buckets.add_connection(id, peer),
+        Event::StreamMessage(id, buf) => buckets.add_stream_packet(id, &buf),
+        Event::StreamLineTooLong(id) => buckets.add_stream_bad_line(id),
+        Event::StreamClosed(id) => buckets.remove_connection(id),
+        Event::StreamRejected => buckets.add_rejected_connection(),
+
+        _ => {}
+    }
+}
+
+/// Send the buckets to every backend and start a new interval.
+///
+/// The backends get a copy, so a slow backend doesn't keep
+/// the admin sessions waiting on the lock.
+fn flush(shared: &Mutex<Buckets>, backends: &mut [Box<dyn Backend>], events: &EventReceiver) {
+    let buckets = {
+        let mut buckets = lock_buckets(shared);
+        buckets.set_queue_stats(events.reset_stats());
+        buckets.process();
+        let flushed = buckets.clone();
+        buckets.reset();
+        flushed
+    };
+    for backend in backends.iter_mut() {
+        backend.flush_buckets(&buckets);
+        backend.flush_events(buckets.events(), buckets.service_checks());
     }
 }
 
 
+/// Run a listener on its own thread, binding it again after errors.
+///
+/// The thread finishes once `stopping` is set.
+fn spawn_listener<S, B, R>(chan: &EventSender,
+                           stopping: &Arc<AtomicBool>,
+                           name: String,
+                           socket: S,
+                           bind: B,
+                           run: R)
+                           -> JoinHandle<()>
+    where S: Send + 'static,
+          B: Fn() -> io::Result<S> + Send + 'static,
+          R: FnMut(S) -> io::Result<()> + Send + 'static
+{
+    let chan = chan.clone();
+    let stopping = stopping.clone();
+    thread::spawn(move || {
+        supervisor::supervise(chan, name, socket, &stopping, BACKOFF, bind, run);
+    })
+}
+
+/// Read metrics from the UDP socket and
+/// publish them into the bucket storage.
+///
+/// Packets larger than `buffer_size` are truncated.
+/// Returns when the server has stopped or `stopping` is set,
+/// or an error if the socket can't be read.
+pub(crate) fn udp_server(chan: EventSender,
+                         socket: UdpSocket,
+                         buffer_size: usize,
+                         stopping: &AtomicBool)
+                         -> io::Result<()> {
+    socket.set_read_timeout(Some(net::READ_TIMEOUT))?;
+    let mut receiver = BatchReceiver::new(socket, buffer_size);
+    while !stopping.load(Ordering::Relaxed) {
+        let count = match receiver.recv() {
+            Ok(count) => count,
+            Err(ref e) if net::timed_out(e) => continue,
+            Err(e) => return Err(e),
+        };
+        // Parse here so several readers can share the work.
+        let packets = (0..count).map(|i| {
+                                    let (buf, truncated) = receiver.datagram(i);
+                                    ParsedPacket::parse(buf, truncated)
+                                })
+                                .collect();
+        if chan.send(Event::Packets(packets)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Accept metric connections on the TCP listener, see `stream::serve()`.
+pub(crate) fn tcp_server(chan: EventSender,
+                         listener: TcpListener,
+                         limit: &ConnectionLimit,
+                         stopping: Arc<AtomicBool>)
+                         -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    stream::serve(chan,
+                  limit,
+                  stopping,
+                  || listener.accept().map(|(stream, peer)| (stream, peer.to_string())),
+                  |stream| {
+                      stream.set_nonblocking(false)?;
+                      stream.set_read_timeout(Some(net::READ_TIMEOUT))
+                  })
+}
+
+/// Accept management connections on the TCP listener.
+///
+/// Each connection is handled on its own thread, up to `max_sessions`
+/// at once, and closed after `idle_timeout` without a command.
+/// Sessions read the shared `buckets` and the queue counters on `chan`.
+/// Returns once `stopping` is set, or an error if the listener
+/// can't accept connections.
+pub(crate) fn admin_server(chan: EventSender,
+                           buckets: Arc<Mutex<Buckets>>,
+                           listener: TcpListener,
+                           max_sessions: usize,
+                           idle_timeout: Duration,
+                           stopping: &AtomicBool)
+                           -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let sessions = ConnectionLimit::new(max_sessions);
+    while let Some((mut stream, _)) = net::accept(stopping, || listener.accept())? {
+        if stream.set_nonblocking(false).is_err() {
+            continue;
+        }
+        let session = match sessions.acquire() {
+            Some(session) => session,
+            None => {
+                let _ = stream.write_all(b"ERROR - too many admin sessions\n");
+                continue;
+            }
+        };
+        let session_send = chan.clone();
+        let session_buckets = buckets.clone();
+        thread::spawn(move || {
+            let _session = session;
+            management::session(stream, &session_buckets, &session_send, idle_timeout);
+        });
+    }
+    Ok(())
+}
+
+
 /// Publishes an event on the channel every interval
 ///
 /// This message is used to push data from the buckets to the backends.
-pub fn flush_timer_loop(chan: Sender<Event>, interval: u64) {
-    let duration = Duration::new(interval, 0);
+/// Flushes happen on multiples of the interval on the wall clock, so
+/// each flush lands in its own graphite retention period. The next
+/// boundary is found from the wall clock after every flush, so flushes
+/// follow clock adjustments and skip the ones missed while the event
+/// loop was busy. Only the wait uses the monotonic clock. A new interval
+/// received on `intervals` replaces the current one.
+pub(crate) fn flush_timer_loop(chan: EventSender, interval: u64, intervals: Receiver<u64>) {
+    let mut interval = interval;
+    let mut last = UNIX_EPOCH;
     loop {
-        sleep(duration);
-        chan.send(Event::TimerFlush).unwrap();
+        // Never before the last flush, in case the clocks disagree
+        // about whether its boundary has passed.
+        let wall = SystemTime::now().max(last);
+        let (timestamp, deadline) = next_flush(interval, wall, Instant::now());
+        let wait = deadline.saturating_duration_since(Instant::now());
+        match intervals.recv_timeout(wait) {
+            Ok(new_interval) => {
+                interval = new_interval;
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if chan.send(Event::TimerFlush(timestamp, deadline)).is_err() {
+            return;
+        }
+        last = UNIX_EPOCH + Duration::from_secs(timestamp as u64);
+    }
+}
+
+/// Find the next multiple of `interval` seconds after `wall`,
+/// and when it is due on the monotonic clock.
+fn next_flush(interval: u64, wall: SystemTime, now: Instant) -> (i64, Instant) {
+    let elapsed = wall.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let next = (elapsed.as_secs() / interval + 1) * interval;
+    (next as i64, now + (Duration::from_secs(next) - elapsed))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buckets::Buckets;
+    use metric::MetricKey;
+    use std::io::{Read, Write};
+    use std::net::{TcpStream, UdpSocket};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::Duration;
+
+    /// Sends the counters from each flush back to the test.
+    struct Capture {
+        chan: Sender<Vec<(MetricKey, f64)>>,
+    }
+
+    impl Backend for Capture {
+        fn flush_buckets(&mut self, buckets: &Buckets) {
+            let counters = buckets.counters()
+                                  .iter()
+                                  .map(|(k, v)| (k.clone(), *v))
+                                  .collect();
+            let _ = self.chan.send(counters);
+        }
+    }
+
+    fn local() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[test]
+    fn test_server_start_and_flush() {
+        let (send, recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .flush_interval(1)
+                         .backend(Box::new(Capture { chan: send }))
+                         .start()
+                         .unwrap();
+        assert!(server.udp_addr().port() > 0);
+        assert!(server.admin_addr().port() > 0);
+
+        let client = UdpSocket::bind(local()).unwrap();
+        client.send_to(b"foo:2|c", server.udp_addr()).unwrap();
+
+        let counters = recv.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(counters.contains(&(MetricKey::from("foo"), 2.0)));
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_multiple_udp_addrs() {
+        let v6 = "[::1]:0".parse().unwrap();
+        if UdpSocket::bind(v6).is_err() {
+            return;
+        }
+        let (send, recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .udp_addr(v6)
+                         .admin_addr(local())
+                         .flush_interval(1)
+                         .backend(Box::new(Capture { chan: send }))
+                         .start()
+                         .unwrap();
+        assert_eq!(2, server.udp_addrs().len());
+        assert!(server.udp_addrs()[1].is_ipv6());
+
+        let client = UdpSocket::bind(local()).unwrap();
+        client.send_to(b"foo:1|c", server.udp_addrs()[0]).unwrap();
+        let client = UdpSocket::bind(v6).unwrap();
+        client.send_to(b"bar:1|c", server.udp_addrs()[1]).unwrap();
+
+        let mut counters = Vec::new();
+        while !(counters.contains(&(MetricKey::from("foo"), 1.0)) &&
+                counters.contains(&(MetricKey::from("bar"), 1.0))) {
+            counters.extend(recv.recv_timeout(Duration::from_secs(5)).unwrap());
+        }
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_default_udp_addr() {
+        assert!(Server::new().udp_addrs.is_empty());
+        assert!(!has_ipv4(&["[::]:0".parse().unwrap()]));
+        assert!(has_ipv4(&["[::]:0".parse().unwrap(), local()]));
+    }
+
+    #[test]
+    fn test_server_udp_workers() {
+        let (send, recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .udp_workers(4)
+                         .flush_interval(1)
+                         .backend(Box::new(Capture { chan: send }))
+                         .start()
+                         .unwrap();
+        assert_eq!(1, server.udp_addrs().len());
+
+        // Different source ports are likely to reach different workers.
+        for _ in 0..8 {
+            let client = UdpSocket::bind(local()).unwrap();
+            client.send_to(b"foo:1|c", server.udp_addr()).unwrap();
+        }
+
+        let mut total = 0.0;
+        while total < 8.0 {
+            let counters = recv.recv_timeout(Duration::from_secs(5)).unwrap();
+            for (key, value) in counters {
+                if key == MetricKey::from("foo") {
+                    total += value;
+                }
+            }
+        }
+        assert_eq!(8.0, total);
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_tcp_ingestion() {
+        let (send, recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .tcp_addr(local())
+                         .admin_addr(local())
+                         .flush_interval(1)
+                         .backend(Box::new(Capture { chan: send }))
+                         .start()
+                         .unwrap();
+
+        let mut stream = TcpStream::connect(server.tcp_addr().unwrap()).unwrap();
+        stream.write_all(b"foo:1|c\nba").unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(b"r:2|c\n").unwrap();
+        drop(stream);
+
+        let mut counters = Vec::new();
+        while !counters.contains(&(MetricKey::from("bar"), 2.0)) {
+            counters = recv.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(counters.contains(&(MetricKey::from("foo"), 1.0)));
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_max_connections() {
+        let server = Server::new()
+                         .udp_addr(local())
+                         .tcp_addr(local())
+                         .admin_addr(local())
+                         .max_connections(1)
+                         .start()
+                         .unwrap();
+        let tcp_addr = server.tcp_addr().unwrap();
+        let mut open = TcpStream::connect(tcp_addr).unwrap();
+        open.write_all(b"foo:1|c\n").unwrap();
+
+        // Wait for the first connection to be read.
+        let mut stats = String::new();
+        while !stats.contains("connections: 1\n") {
+            let mut admin = TcpStream::connect(server.admin_addr()).unwrap();
+            admin.write_all(b"stats\nquit\n").unwrap();
+            stats.clear();
+            admin.read_to_string(&mut stats).unwrap();
+        }
+
+        // The second connection is closed without being read.
+        let mut rejected = TcpStream::connect(tcp_addr).unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(0, rejected.read(&mut buf).unwrap());
+
+        // Wait for the event loop to count the rejection.
+        while !stats.contains("rejected_connections: 1\n") {
+            let mut admin = TcpStream::connect(server.admin_addr()).unwrap();
+            admin.write_all(b"stats\nquit\n").unwrap();
+            stats.clear();
+            admin.read_to_string(&mut stats).unwrap();
+        }
+        assert!(stats.contains("connections: 1\nrejected_connections: 1\n"));
+
+        drop(open);
+        server.shutdown();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_server_unix_sockets() {
+        use std::env;
+        use std::os::unix::net::{UnixDatagram, UnixStream};
+        use std::process;
+
+        let dir = env::temp_dir();
+        let dgram_path = dir.join(format!("statsd-{}-server.dgram", process::id()));
+        let stream_path = dir.join(format!("statsd-{}-server.stream", process::id()));
+        let (send, recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .unix_datagram_path(dgram_path.clone())
+                         .unix_stream_path(stream_path.clone())
+                         .flush_interval(1)
+                         .backend(Box::new(Capture { chan: send }))
+                         .start()
+                         .unwrap();
+
+        let client = UnixDatagram::unbound().unwrap();
+        client.send_to(b"foo:1|c", &dgram_path).unwrap();
+        let mut stream = UnixStream::connect(&stream_path).unwrap();
+        stream.write_all(b"bar:2|c\n").unwrap();
+        drop(stream);
+
+        // The packets may arrive in different flushes.
+        let mut counters = Vec::new();
+        while !(counters.contains(&(MetricKey::from("foo"), 1.0)) &&
+                counters.contains(&(MetricKey::from("bar"), 2.0))) {
+            counters.extend(recv.recv_timeout(Duration::from_secs(5)).unwrap());
+        }
+
+        server.shutdown();
+        assert!(!dgram_path.exists());
+        assert!(!stream_path.exists());
+    }
+
+    #[test]
+    fn test_server_tcp_disabled() {
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .start()
+                         .unwrap();
+        assert_eq!(None, server.tcp_addr());
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_shutdown_handle() {
+        let server = Server::new()
+                         .udp_addr(local())
+                         .tcp_addr(local())
+                         .admin_addr(local())
+                         .start()
+                         .unwrap();
+        let tcp_addr = server.tcp_addr().unwrap();
+        let admin_addr = server.admin_addr();
+        let shutdown = server.shutdown_handle();
+        let waiter = thread::spawn(move || server.join());
+        shutdown.shutdown();
+        waiter.join().unwrap().unwrap();
+
+        // The listeners have closed their sockets once the server is joined.
+        TcpListener::bind(tcp_addr).unwrap();
+        TcpListener::bind(admin_addr).unwrap();
+    }
+
+    #[test]
+    fn test_server_shutdown_releases_ports() {
+        let server = Server::new()
+                         .udp_addr(local())
+                         .tcp_addr(local())
+                         .admin_addr(local())
+                         .start()
+                         .unwrap();
+        let tcp_addr = server.tcp_addr().unwrap();
+        let admin_addr = server.admin_addr();
+        server.shutdown();
+
+        let server = Server::new()
+                         .udp_addr(local())
+                         .tcp_addr(tcp_addr)
+                         .admin_addr(admin_addr)
+                         .start()
+                         .unwrap();
+        assert_eq!(Some(tcp_addr), server.tcp_addr());
+        assert_eq!(admin_addr, server.admin_addr());
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_shutdown_flushes() {
+        let (send, recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .flush_interval(60)
+                         .backend(Box::new(Capture { chan: send }))
+                         .start()
+                         .unwrap();
+        let client = UdpSocket::bind(local()).unwrap();
+        client.send_to(b"foo:3|c", server.udp_addr()).unwrap();
+
+        // Wait for the packet to reach the buckets.
+        let mut stats = String::new();
+        while !stats.contains("total_messages: 1") {
+            let mut admin = TcpStream::connect(server.admin_addr()).unwrap();
+            admin.write_all(b"stats\nquit\n").unwrap();
+            stats.clear();
+            admin.read_to_string(&mut stats).unwrap();
+        }
+
+        server.shutdown();
+        let counters = recv.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(counters.contains(&(MetricKey::from("foo"), 3.0)));
+    }
+
+    #[test]
+    fn test_server_join_timeout() {
+        struct Stuck;
+
+        impl Backend for Stuck {
+            fn flush_buckets(&mut self, _: &Buckets) {
+                thread::sleep(Duration::from_secs(2));
+            }
+        }
+
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .backend(Box::new(Stuck))
+                         .start()
+                         .unwrap();
+        server.shutdown_handle().shutdown();
+        assert!(server.join_timeout(Duration::from_millis(50)).is_none());
+
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .start()
+                         .unwrap();
+        server.shutdown_handle().shutdown();
+        assert!(server.join_timeout(Duration::from_secs(5)).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_server_reload() {
+        let (before, before_recv) = channel();
+        let (after, after_recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .flush_interval(60)
+                         .backend(Box::new(Capture { chan: before }))
+                         .start()
+                         .unwrap();
+        let reloader = server.reload_handle();
+        reloader.reload_failed("bad config".to_string());
+        reloader.reload(Reload::new()
+                            .flush_interval(1)
+                            .backend(Box::new(Capture { chan: after })));
+
+        let client = UdpSocket::bind(local()).unwrap();
+        client.send_to(b"foo:1|c", server.udp_addr()).unwrap();
+        let mut counters = Vec::new();
+        while !counters.contains(&(MetricKey::from("foo"), 1.0)) {
+            counters.extend(after_recv.recv_timeout(Duration::from_secs(5)).unwrap());
+        }
+
+        let mut admin = TcpStream::connect(server.admin_addr()).unwrap();
+        admin.write_all(b"stats\nquit\n").unwrap();
+        let mut stats = String::new();
+        admin.read_to_string(&mut stats).unwrap();
+        assert!(stats.contains("reloads: 2\nfailed_reloads: 1\n"));
+        assert!(stats.contains(&format!(" udp {} - up, restarts: 0, errors: 0\n",
+                                        server.udp_addr())));
+
+        server.shutdown();
+        assert!(before_recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_event_loop_invalid_reload() {
+        let (events, event_recv) = queue::channel(16, QueuePolicy::Block);
+        let (interval, interval_recv) = channel();
+        let buckets = Mutex::new(Buckets::new());
+        let mut backends: Vec<Box<dyn Backend>> = Vec::new();
+        events.send(Event::Reload(Ok(Reload::new().flush_interval(0)))).unwrap();
+        events.send(Event::Shutdown).unwrap();
+
+        event_loop(&event_recv, &buckets, &mut backends, interval).unwrap();
+        let buckets = buckets.into_inner().unwrap();
+        assert_eq!(1, buckets.failed_reloads());
+        assert_eq!(10, buckets.flush_interval());
+        assert!(interval_recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_event_loop_listener_failed() {
+        let (send, recv) = channel();
+        let (events, event_recv) = queue::channel(16, QueuePolicy::Block);
+        let (interval, _interval_recv) = channel();
+        let buckets = Mutex::new(Buckets::new());
+        let mut backends: Vec<Box<dyn Backend>> = vec![Box::new(Capture { chan: send })];
+        events.send(Event::Packets(vec![ParsedPacket::parse(b"foo:1|c", false)])).unwrap();
+        events.send(Event::ListenerFailed("udp".to_string(), "boom".to_string())).unwrap();
+
+        let result = event_loop(&event_recv, &buckets, &mut backends, interval);
+        assert!(result.is_err());
+        assert!(recv.try_recv().is_err());
+        finish(&event_recv, &buckets, &mut backends, Vec::new());
+        let buckets = buckets.into_inner().unwrap();
+        assert_eq!(Some("boom".to_string()), buckets.listeners()["udp"].last_error);
+        assert!(recv.try_recv().unwrap().contains(&(MetricKey::from("foo"), 1.0)));
+    }
+
+    #[test]
+    fn test_finish_flush_time() {
+        /// Sends the flush timestamp back to the test.
+        struct Stamp {
+            chan: Sender<i64>,
+        }
+
+        impl Backend for Stamp {
+            fn flush_buckets(&mut self, buckets: &Buckets) {
+                let _ = self.chan.send(buckets.flush_timestamp());
+            }
+        }
+
+        let (send, recv) = channel();
+        let (_events, event_recv) = queue::channel(16, QueuePolicy::Block);
+        let buckets = Mutex::new(Buckets::new());
+        let mut backends: Vec<Box<dyn Backend>> = vec![Box::new(Stamp { chan: send.clone() }),
+                                                       Box::new(Stamp { chan: send })];
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        finish(&event_recv, &buckets, &mut backends, Vec::new());
+
+        let timestamp = recv.try_recv().unwrap();
+        assert_eq!(0, timestamp % 10);
+        assert!(timestamp > before);
+        assert_eq!(timestamp, recv.try_recv().unwrap());
+    }
+
+    #[test]
+    fn test_server_queue_full() {
+        /// Reports the queue counters, stalling the first flush.
+        struct Stall {
+            chan: Sender<(usize, usize)>,
+            stalled: bool,
+        }
+
+        impl Backend for Stall {
+            fn flush_buckets(&mut self, buckets: &Buckets) {
+                let _ = self.chan.send((buckets.dropped_packets(), buckets.queue_peak_depth()));
+                if !self.stalled {
+                    self.stalled = true;
+                    thread::sleep(Duration::from_millis(500));
+                }
+            }
+        }
+
+        let (send, recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .flush_interval(1)
+                         .queue_capacity(1)
+                         .backend(Box::new(Stall {
+                             chan: send,
+                             stalled: false,
+                         }))
+                         .start()
+                         .unwrap();
+        recv.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Each burst is read while the event loop is stalled.
+        let client = UdpSocket::bind(local()).unwrap();
+        for _ in 0..5 {
+            for _ in 0..10 {
+                client.send_to(b"foo:1|c", server.udp_addr()).unwrap();
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let (dropped, peak) = recv.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(dropped > 0);
+        assert!(peak >= 1);
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_stats_during_slow_flush() {
+        /// Holds every flush until it is released.
+        struct Slow {
+            flushing: Sender<()>,
+            release: Receiver<()>,
+        }
+
+        impl Backend for Slow {
+            fn flush_buckets(&mut self, _: &Buckets) {
+                let _ = self.flushing.send(());
+                let _ = self.release.recv_timeout(Duration::from_secs(5));
+            }
+        }
+
+        let (flushing, flushing_recv) = channel();
+        let (release, release_recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .flush_interval(1)
+                         .backend(Box::new(Slow {
+                             flushing,
+                             release: release_recv,
+                         }))
+                         .start()
+                         .unwrap();
+        flushing_recv.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The event loop is stuck in the backend, the session isn't.
+        let mut admin = TcpStream::connect(server.admin_addr()).unwrap();
+        admin.write_all(b"stats\nquit\n").unwrap();
+        let mut stats = String::new();
+        admin.read_to_string(&mut stats).unwrap();
+        assert!(stats.contains("Good bye!"));
+
+        drop(release);
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_idle_admin_session() {
+        let (send, recv) = channel();
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .admin_max_sessions(1)
+                         .flush_interval(1)
+                         .backend(Box::new(Capture { chan: send }))
+                         .start()
+                         .unwrap();
+        let mut idle = TcpStream::connect(server.admin_addr()).unwrap();
+        idle.write_all(b"help\n").unwrap();
+        let mut help = [0; 21];
+        idle.read_exact(&mut help).unwrap();
+
+        // Metrics are still flushed while the session is open.
+        let client = UdpSocket::bind(local()).unwrap();
+        client.send_to(b"foo:1|c", server.udp_addr()).unwrap();
+        let mut counters = Vec::new();
+        while !counters.contains(&(MetricKey::from("foo"), 1.0)) {
+            counters = recv.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        let mut rejected = TcpStream::connect(server.admin_addr()).unwrap();
+        let mut out = String::new();
+        rejected.read_to_string(&mut out).unwrap();
+        assert_eq!("ERROR - too many admin sessions\n", out);
+
+        // The session can be opened again once the first one has quit.
+        idle.write_all(b"quit\n").unwrap();
+        idle.read_to_string(&mut out).unwrap();
+        let mut stats = String::new();
+        while !stats.contains("END") {
+            let mut admin = TcpStream::connect(server.admin_addr()).unwrap();
+            admin.write_all(b"stats\nquit\n").unwrap();
+            stats.clear();
+            admin.read_to_string(&mut stats).unwrap();
+        }
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_admin_idle_timeout() {
+        let server = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .admin_idle_timeout(1)
+                         .start()
+                         .unwrap();
+        let mut admin = TcpStream::connect(server.admin_addr()).unwrap();
+        admin.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut out = String::new();
+        admin.read_to_string(&mut out).unwrap();
+        assert_eq!("Idle timeout, good bye!\n\n", out);
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_server_invalid_max_connections() {
+        let result = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .max_connections(0)
+                         .start();
+        assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_server_invalid_admin_max_sessions() {
+        let result = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .admin_max_sessions(0)
+                         .start();
+        assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_server_invalid_admin_idle_timeout() {
+        let result = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .admin_idle_timeout(0)
+                         .start();
+        assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_next_flush() {
+        let now = Instant::now();
+        let wall = UNIX_EPOCH + Duration::from_millis(123_400);
+        assert_eq!((130, now + Duration::from_millis(6600)), next_flush(10, wall, now));
+        assert_eq!((124, now + Duration::from_millis(600)), next_flush(1, wall, now));
+
+        // A flush on the boundary waits for the next one.
+        let wall = UNIX_EPOCH + Duration::from_secs(120);
+        assert_eq!((130, now + Duration::from_secs(10)), next_flush(10, wall, now));
+    }
+
+    #[test]
+    fn test_flush_timer_loop() {
+        let (send, recv) = queue::channel(16, QueuePolicy::Block);
+        let (_intervals, interval_recv) = channel();
+        thread::spawn(move || flush_timer_loop(send, 1, interval_recv));
+
+        let mut timestamps = Vec::new();
+        for _ in 0..2 {
+            match recv.recv().unwrap() {
+                Event::TimerFlush(timestamp, due) => {
+                    let wall = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    assert!(wall.as_secs() as i64 >= timestamp);
+                    assert!(due <= Instant::now());
+                    timestamps.push(timestamp);
+                }
+                _ => panic!("Unexpected event"),
+            }
+        }
+        assert_eq!(timestamps[0] + 1, timestamps[1]);
+    }
+
+    #[test]
+    fn test_server_invalid_udp_workers() {
+        let result = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .udp_workers(0)
+                         .start();
+        assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_server_invalid_flush_interval() {
+        let result = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .flush_interval(0)
+                         .start();
+        assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_server_invalid_queue_capacity() {
+        let result = Server::new()
+                         .udp_addr(local())
+                         .admin_addr(local())
+                         .queue_capacity(0)
+                         .start();
+        assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+    }
+
+    #[test]
+    fn test_server_invalid_buffer_size() {
+        for size in [0, MAX_BUFFER_SIZE + 1] {
+            let result = Server::new()
+                             .udp_addr(local())
+                             .admin_addr(local())
+                             .udp_buffer_size(size)
+                             .start();
+            assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+        }
+    }
+
+    #[test]
+    fn test_server_bind_error() {
+        let taken = UdpSocket::bind(local()).unwrap();
+        let result = Server::new()
+                         .udp_addr(taken.local_addr().unwrap())
+                         .admin_addr(local())
+                         .start();
+        assert!(result.is_err());
     }
 }
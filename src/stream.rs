@@ -0,0 +1,219 @@
+//! Newline delimited metrics over stream sockets
+//!
+//! Reads lines from a connection and publishes complete
+//! lines to the event channel. Lines can be split across reads.
+
+use std::io::{self, ErrorKind, Read};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use net::{self, ConnectionLimit};
+use queue::EventSender;
+use server::Event;
+use udp::MAX_BUFFER_SIZE;
+
+
+/// The longest line that is buffered while waiting for a newline.
+pub const MAX_LINE_LENGTH: usize = MAX_BUFFER_SIZE;
+
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+
+/// Accept connections on a stream listener and read each on its own thread.
+///
+/// `accept` polls the non-blocking listener for a stream and its peer's
+/// name, `prepare` sets the stream up to block with `net::READ_TIMEOUT`.
+/// Connections over `limit` are closed and counted as rejected.
+/// Returns once `stopping` is set and the open connections have been
+/// read, or an error if the listener can't accept connections.
+pub fn serve<S, A, P>(chan: EventSender,
+                      limit: &ConnectionLimit,
+                      stopping: Arc<AtomicBool>,
+                      mut accept: A,
+                      prepare: P)
+                      -> io::Result<()>
+    where S: Read + Send + 'static,
+          A: FnMut() -> io::Result<(S, String)>,
+          P: Fn(&S) -> io::Result<()>
+{
+    let mut connections: Vec<JoinHandle<()>> = Vec::new();
+    while let Some((stream, peer)) = net::accept(&stopping, &mut accept)? {
+        connections.retain(|conn| !conn.is_finished());
+        let open = match limit.acquire() {
+            Some(open) => open,
+            None => {
+                if chan.send(Event::StreamRejected).is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        if prepare(&stream).is_err() {
+            continue;
+        }
+        let conn_send = chan.clone();
+        let stopping = stopping.clone();
+        connections.push(thread::spawn(move || {
+            let _open = open;
+            read_lines(conn_send, stream, peer, &stopping);
+        }));
+    }
+    // Readers check `stopping` at least every `READ_TIMEOUT`.
+    for conn in connections {
+        let _ = conn.join();
+    }
+    Ok(())
+}
+
+/// Read lines from a connection until it is closed.
+///
+/// A line longer than `MAX_LINE_LENGTH` is discarded and counted as bad.
+/// A partial line left when the connection closes is still added.
+/// Readers with a timeout stop early once `stopping` is set.
+pub fn read_lines<R: Read>(chan: EventSender,
+                           mut reader: R,
+                           peer: String,
+                           stopping: &AtomicBool) {
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    if chan.send(Event::StreamConnected(id, peer)).is_err() {
+        return;
+    }
+
+    let mut buf = [0; 8192];
+    let mut pending: Vec<u8> = Vec::new();
+    let mut discarding = false;
+    while !stopping.load(Ordering::Relaxed) {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(ref e) if net::timed_out(e) => continue,
+            Err(_) => break,
+        };
+        let mut data = &buf[..len];
+
+        // Skip the rest of a line that was too long.
+        if discarding {
+            match data.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    data = &data[end + 1..];
+                    discarding = false;
+                }
+                None => continue,
+            }
+        }
+        pending.extend_from_slice(data);
+
+        if let Some(end) = pending.iter().rposition(|&b| b == b'\n') {
+            let rest = pending.split_off(end + 1);
+            let lines = mem::replace(&mut pending, rest);
+            if chan.send(Event::StreamMessage(id, lines)).is_err() {
+                return;
+            }
+        }
+        if pending.len() > MAX_LINE_LENGTH {
+            pending.clear();
+            discarding = true;
+            if chan.send(Event::StreamLineTooLong(id)).is_err() {
+                return;
+            }
+        }
+    }
+    if !pending.is_empty() && !discarding {
+        let _ = chan.send(Event::StreamMessage(id, pending));
+    }
+    let _ = chan.send(Event::StreamClosed(id));
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use server::Event;
+    use std::io::{self, Read};
+    use std::sync::atomic::AtomicBool;
+    use queue::{channel, EventReceiver, QueuePolicy};
+
+    /// Returns one chunk per read.
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    /// Times out after the first read and sets the stop flag.
+    struct Stopping<'a>(Option<&'static [u8]>, &'a AtomicBool);
+
+    impl<'a> Read for Stopping<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.take() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    Ok(chunk.len())
+                }
+                None => {
+                    self.1.store(true, Ordering::Relaxed);
+                    Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"))
+                }
+            }
+        }
+    }
+
+    fn collect(recv: EventReceiver) -> Vec<String> {
+        recv.try_iter()
+            .map(|event| match event {
+                Event::StreamConnected(_, peer) => format!("connected {}", peer),
+                Event::StreamMessage(_, lines) => String::from_utf8(lines).unwrap(),
+                Event::StreamLineTooLong(_) => "too long".to_string(),
+                Event::StreamClosed(_) => "closed".to_string(),
+                _ => panic!("Unexpected event"),
+            })
+            .collect()
+    }
+
+    fn read_chunks(chunks: Vec<&[u8]>) -> Vec<String> {
+        let (send, recv) = channel(64, QueuePolicy::Block);
+        let reader = Chunks(chunks.iter().map(|c| c.to_vec()).collect());
+        read_lines(send, reader, "test".to_string(), &AtomicBool::new(false));
+        collect(recv)
+    }
+
+    #[test]
+    fn test_read_lines() {
+        let events = read_chunks(vec![b"foo:1|c\nbar:2|c\n", b"baz:3|c\n"]);
+        assert_eq!(vec!["connected test", "foo:1|c\nbar:2|c\n", "baz:3|c\n", "closed"],
+                   events);
+    }
+
+    #[test]
+    fn test_read_lines_split_across_reads() {
+        let events = read_chunks(vec![b"foo:1|c\nba", b"r:2", b"|c\nbaz:3|c"]);
+        assert_eq!(vec!["connected test", "foo:1|c\n", "bar:2|c\n", "baz:3|c", "closed"],
+                   events);
+    }
+
+    #[test]
+    fn test_read_lines_too_long() {
+        let long = vec![b'a'; 8000];
+        let events = read_chunks(vec![&long, &long, &long, &long, &long, &long, &long, &long,
+                                      &long, b"aaa\nfoo:1|c\n"]);
+        assert_eq!(vec!["connected test", "too long", "foo:1|c\n", "closed"], events);
+    }
+
+    #[test]
+    fn test_read_lines_stopping() {
+        let (send, recv) = channel(64, QueuePolicy::Block);
+        let stopping = AtomicBool::new(false);
+        let reader = Stopping(Some(b"foo:1|c\nba"), &stopping);
+        read_lines(send, reader, "test".to_string(), &stopping);
+        assert_eq!(vec!["connected test", "foo:1|c\n", "ba", "closed"], collect(recv));
+    }
+}
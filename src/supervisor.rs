@@ -0,0 +1,208 @@
+//! Listener supervision
+//!
+//! Each listener runs on its own thread. When its socket fails the
+//! socket is closed and bound again after a delay, so a single error
+//! doesn't leave the server unable to receive metrics.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use net::READ_TIMEOUT;
+use queue::EventSender;
+use server::Event;
+
+
+/// How long to wait before binding a failed listener again.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// The delay after the first failure, doubled for each one after.
+    pub initial: Duration,
+    pub max: Duration,
+    /// Consecutive failures before the listener is given up on.
+    pub attempts: u32,
+    /// Failures are forgotten once a listener has run this long.
+    pub reset_after: Duration,
+}
+
+/// Waits from 100ms up to 5s between attempts, giving up after about 30s.
+pub const BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(100),
+    max: Duration::from_secs(5),
+    attempts: 10,
+    reset_after: Duration::from_secs(60),
+};
+
+impl Backoff {
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u32 << failures.saturating_sub(1).min(16);
+        self.initial.checked_mul(factor).unwrap_or(self.max).min(self.max)
+    }
+}
+
+
+/// Run a listener until the server stops, binding it again after errors.
+///
+/// `run` returns `Ok` once the server has stopped. When it fails the
+/// socket is dropped and `bind` is retried with backoff. A listener that
+/// can't recover is reported with `Event::ListenerFailed`.
+pub fn supervise<S, B, R>(chan: EventSender,
+                          name: String,
+                          socket: S,
+                          stopping: &AtomicBool,
+                          backoff: Backoff,
+                          bind: B,
+                          mut run: R)
+    where B: Fn() -> io::Result<S>,
+          R: FnMut(S) -> io::Result<()>
+{
+    if chan.send(Event::ListenerStarted(name.clone())).is_err() {
+        return;
+    }
+    let mut socket = socket;
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let mut error = match run(socket) {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        if stopping.load(Ordering::Relaxed) {
+            return;
+        }
+        if started.elapsed() >= backoff.reset_after {
+            failures = 0;
+        }
+        socket = loop {
+            eprintln!("Error on {} listener: {}", name, error);
+            if chan.send(Event::ListenerError(name.clone(), error.to_string())).is_err() {
+                return;
+            }
+            failures += 1;
+            if failures > backoff.attempts {
+                eprintln!("Giving up on {} listener", name);
+                let _ = chan.send(Event::ListenerFailed(name, error.to_string()));
+                return;
+            }
+            if !wait(backoff.delay(failures), stopping) {
+                return;
+            }
+            match bind() {
+                Ok(socket) => break socket,
+                Err(e) => error = e,
+            }
+        };
+        eprintln!("Restarted {} listener", name);
+        if chan.send(Event::ListenerRestarted(name.clone())).is_err() {
+            return;
+        }
+    }
+}
+
+/// Sleep for `delay`, returning false early if the server is stopping.
+fn wait(delay: Duration, stopping: &AtomicBool) -> bool {
+    let start = Instant::now();
+    while let Some(remaining) = delay.checked_sub(start.elapsed()) {
+        if stopping.load(Ordering::Relaxed) {
+            return false;
+        }
+        sleep(remaining.min(READ_TIMEOUT));
+    }
+    !stopping.load(Ordering::Relaxed)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use queue::{channel, EventReceiver, QueuePolicy};
+
+    fn quick(attempts: u32) -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(2),
+            attempts,
+            reset_after: Duration::from_secs(60),
+        }
+    }
+
+    fn events(chan: &EventReceiver) -> Vec<String> {
+        chan.try_iter()
+            .map(|event| match event {
+                Event::ListenerStarted(name) => format!("started {}", name),
+                Event::ListenerError(name, error) => format!("error {} {}", name, error),
+                Event::ListenerRestarted(name) => format!("restarted {}", name),
+                Event::ListenerFailed(name, error) => format!("failed {} {}", name, error),
+                _ => panic!("Unexpected event"),
+            })
+            .collect()
+    }
+
+    fn error(message: &str) -> io::Error {
+        io::Error::other(message)
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(Duration::from_millis(100), BACKOFF.delay(1));
+        assert_eq!(Duration::from_millis(400), BACKOFF.delay(3));
+        assert_eq!(Duration::from_secs(5), BACKOFF.delay(10));
+        assert_eq!(Duration::from_secs(5), BACKOFF.delay(u32::MAX));
+    }
+
+    #[test]
+    fn test_supervise_restarts() {
+        let (send, recv) = channel(64, QueuePolicy::Block);
+        let runs = Cell::new(0);
+        supervise(send,
+                  "test".to_string(),
+                  0,
+                  &AtomicBool::new(false),
+                  quick(3),
+                  || Ok(1),
+                  |socket| {
+                      runs.set(runs.get() + 1);
+                      if socket == 0 {
+                          Err(error("read failed"))
+                      } else {
+                          Ok(())
+                      }
+                  });
+        assert_eq!(2, runs.get());
+        assert_eq!(vec!["started test", "error test read failed", "restarted test"],
+                   events(&recv));
+    }
+
+    #[test]
+    fn test_supervise_gives_up() {
+        let (send, recv) = channel(64, QueuePolicy::Block);
+        supervise(send,
+                  "test".to_string(),
+                  0,
+                  &AtomicBool::new(false),
+                  quick(2),
+                  || Err(error("bind failed")),
+                  |_| Err(error("read failed")));
+        assert_eq!(vec!["started test",
+                        "error test read failed",
+                        "error test bind failed",
+                        "error test bind failed",
+                        "failed test bind failed"],
+                   events(&recv));
+    }
+
+    #[test]
+    fn test_supervise_stopping() {
+        let (send, recv) = channel(64, QueuePolicy::Block);
+        supervise(send,
+                  "test".to_string(),
+                  0,
+                  &AtomicBool::new(true),
+                  quick(2),
+                  || Ok(0),
+                  |_| Err(error("read failed")));
+        assert_eq!(vec!["started test"], events(&recv));
+    }
+}
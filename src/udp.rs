@@ -0,0 +1,219 @@
+//! Batched UDP receives
+//!
+//! On Linux several datagrams are read with a single `recvmmsg` call,
+//! other platforms fall back to one `recv_from` per datagram.
+
+use std::io;
+use std::net::UdpSocket;
+
+
+/// The largest receive buffer that can be configured.
+pub const MAX_BUFFER_SIZE: usize = 65536;
+
+/// The number of datagrams read in one batch.
+#[cfg(target_os = "linux")]
+const BATCH_SIZE: usize = 32;
+#[cfg(not(target_os = "linux"))]
+const BATCH_SIZE: usize = 1;
+
+
+/// Reads datagrams from a socket into reusable buffers.
+pub struct BatchReceiver {
+    socket: UdpSocket,
+    buffer_size: usize,
+    buffers: Vec<Vec<u8>>,
+    #[cfg(target_os = "linux")]
+    headers: Headers,
+    // The length and truncation flag of each datagram in the last batch.
+    received: Vec<(usize, bool)>,
+}
+
+impl BatchReceiver {
+    /// Create a receiver that reads datagrams of up to `buffer_size` bytes.
+    ///
+    /// Larger datagrams are truncated to `buffer_size`.
+    pub fn new(socket: UdpSocket, buffer_size: usize) -> BatchReceiver {
+        // An extra byte is read to detect datagrams that didn't fit.
+        let mut buffers = vec![vec![0; buffer_size + 1]; BATCH_SIZE];
+        #[cfg(target_os = "linux")]
+        let headers = Headers::new(&mut buffers);
+        BatchReceiver {
+            socket,
+            buffer_size,
+            buffers,
+            #[cfg(target_os = "linux")]
+            headers,
+            received: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Block until at least one datagram is received.
+    ///
+    /// Returns the number of datagrams read, see `datagram()`.
+    pub fn recv(&mut self) -> io::Result<usize> {
+        self.received.clear();
+        self.recv_batch()?;
+        Ok(self.received.len())
+    }
+
+    /// Get a datagram from the last batch and whether it was truncated.
+    pub fn datagram(&self, index: usize) -> (&[u8], bool) {
+        let (len, truncated) = self.received[index];
+        (&self.buffers[index][..len], truncated)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_batch(&mut self) -> io::Result<()> {
+        use libc;
+        use std::os::unix::io::AsRawFd;
+        use std::ptr;
+
+        let messages = &mut self.headers.messages;
+        for msg in messages.iter_mut() {
+            msg.msg_len = 0;
+            msg.msg_hdr.msg_flags = 0;
+        }
+        let count = loop {
+            // MSG_WAITFORONE blocks for the first datagram, then
+            // returns whatever else is already queued.
+            let count = unsafe {
+                libc::recvmmsg(self.socket.as_raw_fd(),
+                               messages.as_mut_ptr(),
+                               messages.len() as libc::c_uint,
+                               libc::MSG_WAITFORONE,
+                               ptr::null_mut())
+            };
+            if count >= 0 {
+                break count as usize;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        };
+        for msg in &messages[..count] {
+            let len = msg.msg_len as usize;
+            let truncated = msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 || len > self.buffer_size;
+            self.received.push((len.min(self.buffer_size), truncated));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn recv_batch(&mut self) -> io::Result<()> {
+        let (len, _) = self.socket.recv_from(&mut self.buffers[0])?;
+        let truncated = len > self.buffer_size;
+        self.received.push((len.min(self.buffer_size), truncated));
+        Ok(())
+    }
+}
+
+
+/// The `recvmmsg` headers for a receiver's buffers.
+///
+/// They are allocated once and point into the buffers, which are
+/// never resized while the receiver is alive.
+#[cfg(target_os = "linux")]
+struct Headers {
+    // Referenced by `messages` rather than read directly.
+    _iovecs: Vec<libc::iovec>,
+    messages: Vec<libc::mmsghdr>,
+}
+
+#[cfg(target_os = "linux")]
+impl Headers {
+    fn new(buffers: &mut [Vec<u8>]) -> Headers {
+        use std::mem;
+
+        let mut iovecs: Vec<libc::iovec> = buffers.iter_mut()
+                                                  .map(|buf| {
+                                                      libc::iovec {
+                                                          iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                                                          iov_len: buf.len(),
+                                                      }
+                                                  })
+                                                  .collect();
+        let messages = iovecs.iter_mut()
+                             .map(|iov| {
+                                 // Safe as mmsghdr is a plain C struct.
+                                 let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                                 msg.msg_hdr.msg_iov = iov;
+                                 msg.msg_hdr.msg_iovlen = 1;
+                                 msg
+                             })
+                             .collect();
+        Headers {
+            _iovecs: iovecs,
+            messages,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    fn receive_all(receiver: &mut BatchReceiver, expected: usize) -> Vec<(Vec<u8>, bool)> {
+        let mut datagrams = Vec::new();
+        while datagrams.len() < expected {
+            let count = receiver.recv().unwrap();
+            for i in 0..count {
+                let (data, truncated) = receiver.datagram(i);
+                datagrams.push((data.to_vec(), truncated));
+            }
+        }
+        datagrams
+    }
+
+    #[test]
+    fn test_receive_batch() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut receiver = BatchReceiver::new(socket, 16);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"foo:1|c", addr).unwrap();
+        client.send_to(b"bar:2|c\nbaz:3|c\nqux:4|c", addr).unwrap();
+        client.send_to(b"exactly:16bytes|", addr).unwrap();
+
+        let datagrams = receive_all(&mut receiver, 3);
+        assert_eq!((b"foo:1|c".to_vec(), false), datagrams[0]);
+        assert_eq!((b"bar:2|c\nbaz:3|c\n".to_vec(), true), datagrams[1]);
+        assert_eq!((b"exactly:16bytes|".to_vec(), false), datagrams[2]);
+    }
+
+    #[test]
+    fn test_receive_reuses_headers() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut receiver = BatchReceiver::new(socket, 8);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"long:1|c\nmore:2|c", addr).unwrap();
+        assert_eq!((b"long:1|c".to_vec(), true), receive_all(&mut receiver, 1)[0]);
+
+        client.send_to(b"a:1|c", addr).unwrap();
+        assert_eq!((b"a:1|c".to_vec(), false), receive_all(&mut receiver, 1)[0]);
+    }
+
+    #[test]
+    fn test_receive_max_size() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut receiver = BatchReceiver::new(socket, MAX_BUFFER_SIZE);
+
+        let packet = vec![b'a'; 60000];
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&packet, addr).unwrap();
+
+        let datagrams = receive_all(&mut receiver, 1);
+        assert_eq!(60000, datagrams[0].0.len());
+        assert!(!datagrams[0].1);
+    }
+}
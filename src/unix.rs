@@ -0,0 +1,222 @@
+//! Unix domain socket listeners
+//!
+//! Metrics can be sent to a datagram socket, one packet per
+//! message like UDP, or to a stream socket as newline delimited lines.
+
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use events::ParsedPacket;
+use net::{self, ConnectionLimit};
+use queue::EventSender;
+use server::Event;
+use stream;
+
+
+/// Bind a datagram socket at `path` with the given file mode.
+pub fn bind_datagram(path: &Path, mode: u32) -> io::Result<UnixDatagram> {
+    remove_stale(path, |path| {
+        let probe = UnixDatagram::unbound()?;
+        probe.connect(path)
+    })?;
+    bind_private(path, mode, |path| UnixDatagram::bind(path))
+}
+
+/// Bind a stream socket at `path` with the given file mode.
+pub fn bind_stream(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    remove_stale(path, |path| UnixStream::connect(path).map(|_| ()))?;
+    bind_private(path, mode, |path| UnixListener::bind(path))
+}
+
+
+/// Bind a socket and give it `mode` before it appears at `path`.
+///
+/// The socket is bound in a directory only this user can enter, next
+/// to `path`, and linked into place once its mode is set. Binding
+/// directly would leave it open to everyone the umask allows until
+/// the mode is changed.
+fn bind_private<S, B>(path: &Path, mode: u32, bind: B) -> io::Result<S>
+    where B: FnOnce(&Path) -> io::Result<S>
+{
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(ErrorKind::InvalidInput,
+                       format!("{} is not a file path", path.display()))
+    })?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut dir_name = OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", process::id()));
+    let dir = parent.join(dir_name);
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let private = dir.join("socket");
+    let result = bind(&private).and_then(|socket| {
+        fs::set_permissions(&private, Permissions::from_mode(mode))?;
+        fs::hard_link(&private, path)?;
+        Ok(socket)
+    });
+    let _ = fs::remove_file(&private);
+    let _ = fs::remove_dir(&dir);
+    result
+}
+
+
+/// Remove a socket file left behind by a server that didn't shut down.
+///
+/// A socket that still accepts connections belongs to a running server
+/// and is an error, as is a path that isn't a socket.
+fn remove_stale<F>(path: &Path, connect: F) -> io::Result<()>
+    where F: Fn(&Path) -> io::Result<()>
+{
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(ErrorKind::AlreadyExists,
+                                  format!("{} exists and is not a socket", path.display())));
+    }
+    match connect(path) {
+        Ok(_) => {
+            Err(io::Error::new(ErrorKind::AddrInUse,
+                               format!("{} is in use by another process", path.display())))
+        }
+        Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+
+/// Read packets from the datagram socket and
+/// publish them into the bucket storage.
+///
+/// Packets larger than `buffer_size` are truncated.
+/// Returns when the server has stopped or `stopping` is set,
+/// or an error if the socket can't be read.
+pub fn datagram_server(chan: EventSender,
+                       socket: UnixDatagram,
+                       buffer_size: usize,
+                       stopping: &AtomicBool)
+                       -> io::Result<()> {
+    socket.set_read_timeout(Some(net::READ_TIMEOUT))?;
+    // An extra byte is read to detect packets that didn't fit.
+    let mut buf = vec![0; buffer_size + 1];
+    while !stopping.load(Ordering::Relaxed) {
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(ref e) if net::timed_out(e) => continue,
+            Err(e) => return Err(e),
+        };
+        let packet = if len > buffer_size {
+            ParsedPacket::parse(&buf[..buffer_size], true)
+        } else {
+            ParsedPacket::parse(&buf[..len], false)
+        };
+        if chan.send(Event::Packets(vec![packet])).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Accept connections on the stream socket, see `stream::serve()`.
+pub fn stream_server(chan: EventSender,
+                     listener: UnixListener,
+                     path: &Path,
+                     limit: &ConnectionLimit,
+                     stopping: Arc<AtomicBool>)
+                     -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let peer = format!("unix:{}", path.display());
+    stream::serve(chan,
+                  limit,
+                  stopping,
+                  || listener.accept().map(|(stream, _)| (stream, peer.clone())),
+                  |stream| {
+                      stream.set_nonblocking(false)?;
+                      stream.set_read_timeout(Some(net::READ_TIMEOUT))
+                  })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("statsd-{}-{}.sock", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_bind_datagram_mode() {
+        let path = socket_path("mode");
+        let _socket = bind_datagram(&path, 0o620).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o620, mode & 0o777);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_stream_private() {
+        let path = socket_path("private");
+        let _listener = bind_stream(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        UnixStream::connect(&path).unwrap();
+
+        // The directory the socket was bound in is removed.
+        let dir = path.with_file_name(format!(".{}.{}",
+                                              path.file_name().unwrap().to_str().unwrap(),
+                                              process::id()));
+        assert!(!dir.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_removes_stale_socket() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let _listener = bind_stream(&path, 0o660).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_in_use() {
+        let path = socket_path("in-use");
+        let _socket = bind_datagram(&path, 0o660).unwrap();
+        let err = bind_datagram(&path, 0o660).err().unwrap();
+        assert_eq!(ErrorKind::AddrInUse, err.kind());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_not_a_socket() {
+        let path = socket_path("file");
+        File::create(&path).unwrap();
+        let err = bind_stream(&path, 0o660).err().unwrap();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -25,3 +25,43 @@ def test_admin_timers(admin_client, client, admin_server):
 
     assert 'some.metric' in output
     admin_server.kill()
+
+
+def test_admin_sets(admin_client, client, admin_server):
+    time.sleep(1)
+    client('some.set:bob|s\nsome.set:jane|s\nsome.set:bob|s')
+
+    admin_client.connect()
+    admin_client.write('sets\n')
+    output = admin_client.read()
+
+    assert 'some.set: 2' in output
+    admin_server.kill()
+
+
+def test_admin_partial_packet(admin_client, client, admin_server):
+    time.sleep(1)
+    client('good.metric:1|c\nbad.metric:abc|c\nother.metric:2|c')
+
+    admin_client.connect()
+    admin_client.write('counters\n')
+    output = admin_client.read()
+    assert 'good.metric: 1' in output
+    assert 'other.metric: 2' in output
+
+    admin_client.write('stats\n')
+    output = admin_client.read()
+    assert 'bad_messages: 1' in output
+    admin_server.kill()
+
+
+def test_admin_large_packet(admin_client, client, admin_server):
+    time.sleep(1)
+    lines = ['large.metric.%d:1|c' % i for i in range(100)]
+    client('\n'.join(lines))
+
+    admin_client.connect()
+    admin_client.write('counters\n')
+    output = admin_client.read(8192)
+    assert 'large.metric.99: 1' in output
+    admin_server.kill()